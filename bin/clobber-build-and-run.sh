@@ -61,6 +61,7 @@ fi
 
 : ${AXON_SERVER_PORT=8024}
 : ${API_SERVER_PORT=8181}
+: ${REST_SERVER_PORT=8282}
 : ${ENSEMBLE_NAME=rustic}
 "${BIN}/create-local-settings.sh"
 
@@ -106,7 +107,6 @@ function waitForDockerComposeReady() {
     cd "${PROJECT}"
 
 #    src/bin/generate-root-key-pair.sh
-#    src/bin/generate-module-for-trusted-keys.sh
 
     if "${DO_BUILD}"
     then
@@ -115,8 +115,8 @@ function waitForDockerComposeReady() {
 
         if "${DO_BUILD_BACK_END}"
         then
-            # Generate module "trusted_generated".
-            "${BIN}/generate-module-for-trusted-keys.sh" -v
+            # Collect the public keys that are loaded at startup.
+            "${BIN}/collect-trusted-keys.sh" -v
 
             # Build server executables from Rust sources
             info "Build executables for the back-end"
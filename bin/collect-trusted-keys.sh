@@ -0,0 +1,28 @@
+#!/bin/bash
+
+BIN="$(cd "$(dirname "$0")" ; pwd)"
+PROJECT="$(dirname "${BIN}")"
+TRUSTED_KEYS_DIR="${PROJECT}/target/trusted-keys"
+
+source "${BIN}/verbose.sh"
+source "${PROJECT}/etc/settings-local.sh"
+
+rm -rf "${TRUSTED_KEYS_DIR}"
+mkdir -p "${TRUSTED_KEYS_DIR}"
+
+(
+  cd "${PROJECT}" || exit 1
+  N=0
+  for F in "${ROOT_PRIVATE_KEY}.pub" "${ADDITIONAL_TRUSTED_KEYS[@]}"
+  do
+    if [[ -z "${F}" ]]
+    then
+      continue
+    fi
+    log ">>> Trusted key: [${F}]"
+    N=$((${N} + 1))
+    cp "${F}" "${TRUSTED_KEYS_DIR}/key-${N}.pub"
+  done
+)
+
+"${SILENT}" || ls -l "${TRUSTED_KEYS_DIR}" | sed -e 's/^/+/'
@@ -33,9 +33,12 @@ fi
           echo "${USER_ID}=${PASSWORD_ENCRYPTED}"
         done
 
+    echo ">>> Properties"
+    cat "${PROJECT}/etc/secrets-local.yaml" \
+      | docker run --rm -i karlkfi/yq -r '.users | to_entries[] | "roles." + .key + "=" + ((.value.roles // []) | join(","))'
+
     if [[ -f ""${PROJECT}/etc/application-local.yaml"" ]]
     then
-      echo ">>> Properties"
       cat "${PROJECT}/etc/application-local.yaml" \
         | docker run --rm -i karlkfi/yq -r --stream \
             '. | select(length > 1) | .[0][0] + (reduce .[0][1:][] as $item ("" ; . + "." + ($item | tostring))) + "=" + .[1]'
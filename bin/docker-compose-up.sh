@@ -21,6 +21,7 @@ DOCKER_REPOSITORY='dendrite2go'
 : ${ENSEMBLE_NAME=example}
 : ${EXTRA_VOLUMES:=}
 source "${PROJECT}/etc/settings-local.sh"
+: ${REST_SERVER_PORT:=8282}
 
 VOLUMES=''
 if [[ -n "${EXTRA_VOLUMES}" ]]
@@ -2,7 +2,6 @@
 
 BIN="$(cd "$(dirname "$0")" ; pwd)"
 PROJECT="$(dirname "${BIN}")"
-PROTO_DIR="${PROJECT}/proto"
 
 source "${BIN}/verbose.sh"
 
@@ -13,58 +12,69 @@ then
   shift 2
 fi
 
+# The JWT that is sent in the authorization header; obtain one with --authorize.
+TOKEN="${GRPCURL_TOKEN}"
+if [[ ".$1" = '.--token' ]]
+then
+  TOKEN="$2"
+  shift 2
+fi
+
 VARIABLE='message'
 VALUE='{}'
 case "$1" in
 --greet)
-  PROTO="proto_example.proto"
   PORT='3000'
   URL='proto_example.GreeterService/Greet'
   shift
   ;;
 --record)
-  PROTO="proto_example.proto"
   PORT='3000'
   URL='proto_example.GreeterService/Record'
   VARIABLE=''
   shift
   ;;
 --stop)
-  PROTO="proto_example.proto"
   PORT='3000'
   URL='proto_example.GreeterService/Stop'
   VARIABLE=''
   shift
   ;;
+--retract)
+  PORT='3000'
+  URL='proto_example.GreeterService/Retract'
+  VARIABLE='sequenceNumber'
+  shift
+  ;;
 --greetings)
-  PROTO="proto_example.proto"
   PORT='3000'
   URL='proto_example.GreeterService/Greetings'
   VARIABLE=''
   shift
   ;;
+--follow)
+  PORT='3000'
+  URL='proto_example.GreeterService/Greetings'
+  VARIABLE=''
+  VALUE='{"mode":"REPLAY_THEN_FOLLOW"}'
+  shift
+  ;;
 --search)
-  PROTO="proto_example.proto"
   PORT='3000'
   URL='proto_example.GreeterService/Search'
   VARIABLE='query'
   shift
   ;;
 --direct)
-  PROTO="proto_example.proto"
   PORT='8181'
   URL='proto_example.GreeterService/Greet'
   shift
   ;;
 --authorize)
-  PROTO="dendrite_config.proto"
   PORT='3000'
-  URL='dendrite_config.ConfigurationService/Authorize'
+  URL='proto_dendrite_config.ConfigurationService/Authorize'
   VARIABLE=''
   shift
-  PROTO_DIR="${PROJECT}/target/proto"
-  mkdir -p "${PROTO_DIR}"
-  sed -e '/^package/s/ proto_/ /' "${PROJECT}/proto/proto_dendrite_config.proto" > "${PROTO_DIR}/dendrite_config.proto"
   ;;
 *)
   exit 1
@@ -84,6 +94,13 @@ else
   PAYLOAD="$(echo "{'${VARIABLE}':'${VALUE}'}" | tr \'\" \"\')"
 fi
 
+HEADER_FLAGS=()
+if [[ -n "${TOKEN}" ]]
+then
+  HEADER_FLAGS=(-H "authorization: ${TOKEN}")
+fi
+
+# There are no local proto files: grpcurl discovers the services through gRPC reflection.
 docker run --rm -v "${PROJECT}:${PROJECT}" -w "${PROJECT}" -ti \
-  fullstorydev/grpcurl -plaintext -import-path "${PROTO_DIR}" -proto "${PROTO}" \
+  fullstorydev/grpcurl -plaintext "${HEADER_FLAGS[@]}" \
     -d "${PAYLOAD}" "${HOST}:${PORT}" "${URL}"
@@ -1,8 +1,18 @@
+use std::env;
+use std::path::PathBuf;
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+    let mut config = prost_build::Config::new();
+    config.message_attribute(".", "#[serde(default)]");
+    // Proto3 `optional` fields need this flag on protoc 3.12 to 3.14, the version that docker/rust/Dockerfile installs.
+    config.protoc_arg("--experimental_allow_proto3_optional");
     tonic_build::configure()
         .out_dir("src")
-        .type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]\n#[serde(default)]")
-        .compile(
+        .file_descriptor_set_path(out_dir.join("dendrite_example_descriptor.bin"))
+        .type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
+        .compile_with_config(
+            config,
             &[
                 "proto/proto_example.proto",
                 "proto/proto_dendrite_config.proto"
@@ -5,6 +5,7 @@ ENSEMBLE_NAME=rustic
 ENSEMBLE_IMAGE_VERSION='0.0.1-SNAPSHOT'
 UI_SERVER_PORT='3000'
 API_SERVER_PORT='8181'
+REST_SERVER_PORT='8282'
 AXON_SERVER_PORT='8024'
 AXON_VERSION='4.3.1'
 ELASTIC_SEARCH_VERSION='7.6.1'
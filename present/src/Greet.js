@@ -29,6 +29,7 @@ class Greet extends Component {
                    <input type='password' id='password' className='trailing'/>
                    <input type='submit' id='login' value=' Login ' onClick={this.login} className='trailing'/>
                 </p>
+                <p><input type='text' id='greeter-id' placeholder='greeter (default: you)'/></p>
                 <p><input type='submit' id='record' value=' Record ' onClick={this.handleRecord}/>
                    <input type='submit' id='stop' value=' Stop ' onClick={this.handleStop} className='trailing'/>
                 </p>
@@ -36,7 +37,13 @@ class Greet extends Component {
                    <input type='submit' id='submit-greeting' value=' Go! ' onClick={this.handleSubmit} className='trailing'/>
                    <input type='submit' id='submit-query' value=' Submit Lucene query ' onClick={this.handleQuery} className='trailing'/>
                 </p>
-                <p><input type='submit' id='refresh-greetings' value=' Refresh! ' onClick={this.handleRefresh}/></p>
+                <p><input type='submit' id='refresh-greetings' value=' Refresh! ' onClick={this.handleRefresh}/>
+                   <select id='greetings-mode' className='trailing' defaultValue={example.GreetingsMode.REPLAY_ONLY}>
+                       <option value={example.GreetingsMode.REPLAY_ONLY}>Replay</option>
+                       <option value={example.GreetingsMode.LIVE_ONLY}>Live</option>
+                       <option value={example.GreetingsMode.REPLAY_THEN_FOLLOW}>Replay, then follow</option>
+                   </select>
+                </p>
                 <div id='greetings'><div><i>greetings appear here</i></div></div>
             </div>
         );
@@ -71,12 +78,18 @@ class Greet extends Component {
         });
     }
 
+    greeterId() {
+        // An empty greeter id refers to the greeter of the user that logged in.
+        return document.getElementById('greeter-id').value.trim();
+    }
+
     handleSubmit(_event) {
         const message = document.getElementById('message').value;
         console.log('Submit: message:', message);
         const request = new example.Greeting();
         console.log('Submit: new request:', request);
         request.setMessage(message);
+        request.setGreeterId(this.greeterId());
         console.log('Submit: request:', request);
         const client = new example.GreeterServiceClient(this.greetUrl);
         console.log('Submit: client:', client);
@@ -104,7 +117,7 @@ class Greet extends Component {
         console.log('Query: new request:', searchQueryRequest);
         searchQueryRequest.setQuery(query);
         console.log('Query: request:', searchQueryRequest);
-        const response = client.search(searchQueryRequest);
+        const response = client.search(searchQueryRequest, {"authorization": this.state.jwt});
 
         console.log('Query: response:', response);
         response.on('data', function(r) {
@@ -135,9 +148,17 @@ class Greet extends Component {
         const container = document.getElementById('greetings');
         container.innerHTML = '';
 
-        const request = new example.Empty();
+        if (this.greetingsStream) {
+            // The live modes keep the stream open, so stop following the previous one.
+            this.greetingsStream.cancel();
+        }
+
+        const request = new example.GreetingsRequest();
+        request.setGreeterId(this.greeterId());
+        request.setMode(Number(document.getElementById('greetings-mode').value));
         console.log('Refresh: new request:', request);
-        const response = client.greetings(request);
+        const response = client.greetings(request, {"authorization": this.state.jwt});
+        this.greetingsStream = response;
 
         console.log('Refresh: response:', response);
         response.on('data', function(r) {
@@ -160,11 +181,12 @@ class Greet extends Component {
     }
 
     handleRecord(_event) {
-        const request = new example.Empty();
+        const request = new example.GreeterReference();
+        request.setGreeterId(this.greeterId());
         console.log('Record: new request:', request);
         const client = new example.GreeterServiceClient(this.greetUrl);
         console.log('Record: client:', client);
-        const response = client.record(request);
+        const response = client.record(request, {"authorization": this.state.jwt});
         console.log('Record: response:', response);
         response.on('data', function(r) {console.log('Record response data:', r);})
         response.on('status', function(status) {
@@ -175,11 +197,12 @@ class Greet extends Component {
     }
 
     handleStop(_event) {
-        const request = new example.Empty();
+        const request = new example.GreeterReference();
+        request.setGreeterId(this.greeterId());
         console.log('Stop: new request:', request);
         const client = new example.GreeterServiceClient(this.greetUrl);
         console.log('Stop: client:', client);
-        const response = client.stop(request);
+        const response = client.stop(request, {"authorization": this.state.jwt});
         console.log('Stop: response:', response);
         response.on('data', function(r) {console.log('Stop response data:', r);})
         response.on('status', function(status) {
@@ -1,83 +1,191 @@
 use dendrite::auth as dendrite_auth;
 use dendrite::axon_utils::{AxonServerHandle, AxonServerHandleAsyncTrait, platform_worker_for, WorkerControl};
 use dendrite::elasticsearch::replica;
-use log::{debug, error, info, warn};
+use log::{debug, error, info};
 use prost::Message;
 use std::error::Error;
+use std::sync::Arc;
 use anyhow::anyhow;
 use async_channel::{bounded, Receiver};
+use futures_util::future::{try_join_all, BoxFuture};
 use futures_util::FutureExt;
-use tokio::signal::unix::{signal,SignalKind};
+use tokio::select;
+use tokio::sync::watch;
 use tonic::transport::Server;
-use tonic::{Request, Status};
 use uuid::Uuid;
 use crate::example_api::{GreeterServer, init};
-use crate::example_command::handle_commands;
-use crate::example_event::{process_events, trusted_generated};
-use crate::example_query::process_queries;
+use crate::example_auth::{authenticate, authenticate_if_present};
+use crate::example_command::handle_commands_with;
+use crate::example_config::ApplicationConfig;
+use crate::example_event::process_events_with;
+use crate::example_grpc_web::grpc_web_config;
+use crate::example_health::{health_service, monitor_axon_server, shut_down};
+use crate::example_logging::set_log_format;
+use crate::example_metrics::{serve as serve_metrics, worker_up};
+use crate::example_query::process_queries_with;
+use crate::example_reflection::reflection_service;
+use crate::example_rest::serve as serve_rest;
+use crate::example_shutdown::{graceful_shutdown, register, shutdown_signal};
+use crate::example_supervisor::supervise;
+use crate::example_tracing::{init_tracing, shutdown_tracing};
+use crate::example_tls::{load_certificates, reload_certificates_on_hangup, tls_incoming};
+use crate::example_trust_api::TrustStoreServer;
+use crate::example_trust_command::handle_trust_commands_with;
+use crate::example_trusted_keys::{load_trusted_keys, reload_on_hangup};
+use crate::proto_dendrite_config::configuration_service_server::ConfigurationServiceServer;
 use crate::proto_example::greeter_service_server::GreeterServiceServer;
 use crate::proto_example::{
     GreetedEvent, PropertyChangedEvent, StartedRecordingEvent, StoppedRecordingEvent,
 };
 
 pub async fn application() -> Result<(), Box<dyn Error>> {
-    let signal_stream = signal(SignalKind::terminate())?;
-    let greeter_server = init().await.unwrap();
+    let config = Arc::new(ApplicationConfig::load()?);
+    set_log_format(config.logging.format);
+    init_tracing(&config.tracing)?;
+    let shutdown_requested = shutdown_signal()?;
+    let greeter_server = init(config.clone()).await.unwrap();
     let axon_server_handle = &greeter_server.axon_server_handle.clone();
+    let labels = &config.workers;
+    monitor_axon_server(axon_server_handle.clone(), config.clone());
 
-    axon_server_handle.spawn("Platform", platform_worker_for("Rustic"))?;
+    let platform_name = config.axon_server.platform_name.clone();
+    axon_server_handle.spawn(&*labels.platform, supervise("platform", &config, move |handle, worker_control| {
+        let platform_worker = platform_worker_for(&platform_name);
+        Box::pin(platform_worker(handle, worker_control).map(Ok))
+    }))?;
+
+    axon_server_handle.spawn(&*labels.command, handle_commands_with(config.clone()))?;
+    axon_server_handle.spawn(&*labels.trust_command, handle_trust_commands_with(config.clone()))?;
+    axon_server_handle.spawn(&*labels.event, process_events_with(config.clone()))?;
 
-    axon_server_handle.spawn("Command", &handle_commands)?;
-    axon_server_handle.spawn("Elastic", &process_events)?;
+    axon_server_handle.spawn(&*labels.replica, supervise("replica", &config, |handle, worker_control| {
+        let transcoders = replica::Transcoders::new()
+            .insert_ref("GreetedEvent", &GreetedEvent::decode)
+            .insert_ref("StartedRecordingEvent", &StartedRecordingEvent::decode)
+            .insert_ref("StoppedRecordingEvent", &StoppedRecordingEvent::decode)
+            .insert_ref("PropertyChangedEvent", &PropertyChangedEvent::decode);
+        Box::pin(replica::process_events_with(transcoders)(handle, worker_control).map(Ok))
+    }))?;
 
-    let transcoders = replica::Transcoders::new()
-        .insert_ref("GreetedEvent", &GreetedEvent::decode)
-        .insert_ref("StartedRecordingEvent", &StartedRecordingEvent::decode)
-        .insert_ref("StoppedRecordingEvent", &StoppedRecordingEvent::decode)
-        .insert_ref("PropertyChangedEvent", &PropertyChangedEvent::decode);
-    axon_server_handle.spawn("Replica",replica::process_events_with(transcoders))?;
+    load_trusted_keys(&config.trusted_keys)?;
+    reload_on_hangup(config.clone())?;
+    if config.server.tls.enabled {
+        load_certificates(&config.server.tls)?;
+        reload_certificates_on_hangup(config.clone())?;
+    }
+    axon_server_handle.spawn(&*labels.auth, supervise("auth", &config, |handle, worker_control| {
+        Box::pin(dendrite_auth::process_events(handle, worker_control).map(Ok))
+    }))?;
 
-    trusted_generated::init()?;
-    axon_server_handle.spawn("Auth",&dendrite_auth::process_events)?;
+    axon_server_handle.spawn(&*labels.query, process_queries_with(config.clone()))?;
 
-    axon_server_handle.spawn("Query",&process_queries)?;
+    let trust_store_server = TrustStoreServer {
+        axon_server_handle: axon_server_handle.clone(),
+        config: config.clone(),
+    };
 
     info!("Starting gRPC server");
-    let (tx, rx) = bounded(10);
-    let id = axon_server_handle.spawn("gRPC server", Box::new(|handle, control_channel| {
+    let (tx, rx) = bounded(config.channels.server_id);
+    let server_config = config.clone();
+    let server_label = labels.grpc_server.clone();
+    let id = axon_server_handle.spawn(&*labels.grpc_server, Box::new(|handle, control_channel| {
         Box::pin(
-            run_server(greeter_server, control_channel)
+            run_server(greeter_server, trust_store_server, server_config, control_channel)
                 .then(|result| {
                     send_termination_notification(
                         handle,
                         result.map_err(|e| anyhow!(e.to_string())),
-                        "gRPC Server",
+                        server_label,
                         rx
                     )
                 })
         )
     }))?;
-    tx.send(id).await.map_err(|e| {
+    if let Err(e) = tx.send(id).await {
         error!("Error sending server id: {:?}", e);
-        e
-    })?;
+        return Err(e.into());
+    }
 
-    let mut signal = Some(signal_stream);
-    axon_server_handle.join_workers_with_signal(&mut signal).await?;
+    select! {
+        result = axon_server_handle.join_workers() => result?,
+        signal_name = shutdown_requested => {
+            info!("Received {}: shutting down", signal_name);
+            graceful_shutdown(&config.shutdown).await;
+        }
+    }
+    shutdown_tracing();
     Ok(())
 }
 
-async fn run_server(greeter_server: GreeterServer, worker_control: WorkerControl) -> anyhow::Result<()> {
-    debug!("Run server: {:?}", worker_control.get_label());
+async fn run_server(greeter_server: GreeterServer, trust_store_server: TrustStoreServer, config: Arc<ApplicationConfig>, worker_control: WorkerControl) -> anyhow::Result<()> {
+    let label = worker_control.get_label().to_string();
+    debug!("Run server: {:?}", label);
+    let turn = register("grpc_server");
     let control_channel = worker_control.get_control_channel().clone();
-    let addr = "0.0.0.0:8181".parse()?;
-    Server::builder()
-        .add_service(GreeterServiceServer::with_interceptor(
-            greeter_server,
-            interceptor,
-        ))
-        .serve_with_shutdown(addr, control_channel.recv().map(|_r|()))
-        .await.map_err(|e| anyhow!(e))
+    let addr = config.server.socket_address()?;
+    let reflection = if config.server.reflection {
+        Some(reflection_service()?)
+    } else {
+        None
+    };
+    let grpc_web = grpc_web_config(&config.server.grpc_web);
+    let rest_greeter_server = greeter_server.clone();
+    let greeter_service = GreeterServiceServer::with_interceptor(greeter_server, authenticate);
+    let trust_store_service = ConfigurationServiceServer::with_interceptor(trust_store_server, authenticate_if_present);
+    // Each service is added either as is, or wrapped for gRPC-Web, which also accepts plain gRPC.
+    let greeter_web_service = grpc_web.as_ref().map(|web| web.enable(greeter_service.clone()));
+    let trust_store_web_service = grpc_web.as_ref().map(|web| web.enable(trust_store_service.clone()));
+    let plain = grpc_web.is_none();
+    // A single listener on the control channel and the shutdown turn tells all listeners to stop.
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let shutdown_requested = turn.requested();
+    tokio::spawn(async move {
+        select! {
+            _command = control_channel.recv() => shut_down().await,
+            _requested = shutdown_requested => {}
+        }
+        stop_tx.send(true).ok();
+    });
+    let router = Server::builder()
+        .accept_http1(grpc_web.is_some())
+        .add_service(health_service().await)
+        .add_optional_service(reflection)
+        .add_optional_service(plain.then_some(greeter_service))
+        .add_optional_service(greeter_web_service)
+        .add_optional_service(plain.then_some(trust_store_service))
+        .add_optional_service(trust_store_web_service);
+    let tls = config.server.tls.enabled;
+    let grpc_stop = stop_rx.clone();
+    let grpc_server = async move {
+        if tls {
+            let incoming = tls_incoming(addr).await?;
+            router.serve_with_incoming_shutdown(incoming, stopped(grpc_stop)).await
+        } else {
+            router.serve_with_shutdown(addr, stopped(grpc_stop)).await
+        }
+        .map_err(|e| anyhow!(e))
+    };
+    let mut servers: Vec<BoxFuture<anyhow::Result<()>>> = vec![grpc_server.boxed()];
+    if config.rest.enabled {
+        let rest_address = config.rest.socket_address()?;
+        servers.push(serve_rest(rest_address, Arc::new(rest_greeter_server), stopped(stop_rx.clone())).boxed());
+    }
+    if config.metrics.enabled {
+        servers.push(serve_metrics(config.metrics.socket_address()?, stopped(stop_rx)).boxed());
+    }
+    worker_up(&label, true);
+    let result = try_join_all(servers).await;
+    worker_up(&label, false);
+    drop(turn);
+    result.map(|_| ())
+}
+
+async fn stopped(mut stop: watch::Receiver<bool>) {
+    while !*stop.borrow() {
+        if stop.changed().await.is_err() {
+            break;
+        }
+    }
 }
 
 async fn send_termination_notification<S: Into<String>>(handle: AxonServerHandle, result: anyhow::Result<()>, label: S, id: Receiver<Uuid>) {
@@ -97,17 +205,3 @@ async fn send_termination_notification<S: Into<String>>(handle: AxonServerHandle
         error!("Termination notification send failed: {:?}: {:?}", label, e)
     }
 }
-
-fn interceptor(req: Request<()>) -> Result<Request<()>, Status> {
-    let token = match req.metadata().get("authorization") {
-        Some(token) => token.to_str().unwrap(),
-        None => "",
-    };
-    debug!("Using token: [{:?}]", token);
-    let credentials = dendrite_auth::verify_jwt(token);
-    match credentials {
-        Ok(claims) => debug!("Credentials: [{:?}]", claims),
-        Err(error) => warn!("JWT parsing error: {:?}", error),
-    };
-    Ok(req)
-}
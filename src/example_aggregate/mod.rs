@@ -0,0 +1,738 @@
+//! Event-sourced aggregates with snapshots.
+//!
+//! This is a variant of the aggregate support of `dendrite::axon_utils` (`AggregateDefinition` and `AggregateContext`)
+//! that stores a snapshot of the projection of an aggregate in the snapshot store of AxonServer
+//! every `snapshots.interval` events. A projection that is not in the cache is restored from the latest snapshot, and
+//! only the events after that snapshot are replayed through the sourcing handlers. The command stream itself is left
+//! to the `command_worker` of dendrite.
+//!
+//! Command handlers and sourcing handlers are the usual `#[dendrite_macros::command_handler]` and
+//! `#[dendrite_macros::event_sourcing_handler]` functions. Only the type of the context of the command handlers differs.
+//!
+//! An `AggregateTestFixture` runs the same handlers in-process, against a given list of past events instead of the
+//! event store.
+
+use crate::example_config::SnapshotConfig;
+use crate::example_error::DomainError;
+use anyhow::{anyhow, Result};
+use async_lock::Mutex;
+use bytes::Bytes;
+use dendrite::axon_server::command::Command;
+use dendrite::axon_server::common::meta_data_value::Data;
+use dendrite::axon_server::common::MetaDataValue;
+use dendrite::axon_server::event::event_store_client::EventStoreClient;
+use dendrite::axon_server::event::{Event, GetAggregateEventsRequest};
+use dendrite::axon_server::SerializedObject;
+use dendrite::axon_utils::{
+    command_worker as relay_command_worker, create_aggregate_definition as create_relay_definition,
+    empty_aggregate_registry, empty_handler_registry, AggregateContext as RelayContext, AggregateContextTrait,
+    AggregateRegistry, ApplicableTo, AxonServerHandle, HandlerRegistry, TheHandlerRegistry, WorkerControl,
+};
+use dendrite::intellij_work_around::Debuggable;
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use lru::LruCache;
+use prost::{DecodeError, Message};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tonic::transport::Channel;
+use tonic::Request;
+use uuid::Uuid;
+
+const CACHE_SIZE: usize = 1024;
+const COMMAND_NAME_KEY: &str = "dendrite::command_name";
+const COMMAND_ID_KEY: &str = "dendrite::command_id";
+const CORRELATION_ID_KEY: &str = "dendrite::correlation_id";
+
+/// The bounds on the type of the projection of an aggregate.
+pub trait Projection: Message + Default + Clone + Debug + Send + Sync + 'static {}
+
+impl<P> Projection for P where P: Message + Default + Clone + Debug + Send + Sync + 'static {}
+
+/// The bounds on the type of the settings of an aggregate, _i.e._, the configuration that its command handlers need.
+pub trait Settings: Send + Sync + 'static {}
+
+impl<S> Settings for S where S: Send + Sync + 'static {}
+
+/// Registry of the command handlers of an aggregate.
+pub type CommandHandlerRegistry<P, S = ()> = TheHandlerRegistry<Arc<Mutex<AggregateContext<P, S>>>, Command, SerializedObject>;
+
+/// Registry of the sourcing handlers of an aggregate.
+pub type SourcingHandlerRegistry<P> = TheHandlerRegistry<P, Event, P>;
+
+/// The complete definition of an aggregate, as needed by function `command_worker`.
+pub struct AggregateDefinition<P: Projection, S: Settings = ()> {
+    pub projection_name: String,
+    command_names: Vec<String>,
+    empty_projection: fn() -> P,
+    command_handler_registry: CommandHandlerRegistry<P, S>,
+    sourcing_handler_registry: SourcingHandlerRegistry<P>,
+    settings: S,
+    snapshot_revision: String,
+    snapshot_interval: i64,
+    cache: std::sync::Mutex<LruCache<String, (i64, P)>>,
+}
+
+impl<P: Projection, S: Settings> Debug for AggregateDefinition<P, S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[AggregateDefinition:{:?}]", self.projection_name)
+    }
+}
+
+/// Creates a new aggregate definition.
+///
+/// AxonServer needs the names of the commands before the first command arrives, so they are listed explicitly. Each
+/// of them must have a handler in the command handler registry. Snapshots with a different `projection_revision` are
+/// ignored, so change the revision when the meaning of the fields of the projection changes.
+///
+/// The `settings` are available to the command handlers through `AggregateContext::settings`. Sourcing handlers only
+/// see the events, so a setting that affects the projection must be recorded in the emitted events.
+#[allow(clippy::too_many_arguments)]
+pub fn create_aggregate_definition<P: Projection, S: Settings>(
+    projection_name: &str,
+    projection_revision: &str,
+    command_names: &[&str],
+    empty_projection: fn() -> P,
+    command_handler_registry: CommandHandlerRegistry<P, S>,
+    sourcing_handler_registry: SourcingHandlerRegistry<P>,
+    settings: S,
+    config: &SnapshotConfig,
+) -> Result<AggregateDefinition<P, S>> {
+    for command_name in command_names {
+        if command_handler_registry.get(command_name).is_none() {
+            return Err(anyhow!("Missing command handler: {:?}: {:?}", projection_name, command_name));
+        }
+    }
+    let cache_size = NonZeroUsize::new(CACHE_SIZE).ok_or_else(|| anyhow!("Empty cache"))?;
+    Ok(AggregateDefinition {
+        projection_name: projection_name.to_string(),
+        command_names: command_names.iter().map(|name| name.to_string()).collect(),
+        empty_projection,
+        command_handler_registry,
+        sourcing_handler_registry,
+        settings,
+        snapshot_revision: projection_revision.to_string(),
+        snapshot_interval: config.interval as i64,
+        cache: std::sync::Mutex::new(LruCache::new(cache_size)),
+    })
+}
+
+impl<P: Projection, S: Settings> AggregateDefinition<P, S> {
+    /// Applies an event to a projection with the matching sourcing handler.
+    async fn apply(&self, event: Event, projection: P) -> Result<P> {
+        let payload = event
+            .payload
+            .clone()
+            .ok_or_else(|| anyhow!("Missing payload: {:?}", event.message_identifier))?;
+        let sourcing_handler = self
+            .sourcing_handler_registry
+            .get(&payload.r#type)
+            .ok_or_else(|| anyhow!("Missing sourcing handler for {}", payload.r#type))?;
+        let applied = sourcing_handler.handle(payload.data, event, projection.clone()).await?;
+        Ok(applied.unwrap_or(projection))
+    }
+
+    /// Whether a snapshot is due after the events up to and including `new_seq` were stored on top of `old_seq`.
+    fn snapshot_due(&self, old_seq: i64, new_seq: i64) -> bool {
+        self.snapshot_interval > 0 && (new_seq + 1) / self.snapshot_interval > (old_seq + 1) / self.snapshot_interval
+    }
+
+    fn cached(&self, aggregate_id: &str) -> Result<Option<(i64, P)>> {
+        let mut cache = self.cache.lock().map_err(|e| anyhow!(e.to_string()))?;
+        Ok(cache.get(aggregate_id).cloned())
+    }
+
+    fn cache(&self, aggregate_id: &str, seq: i64, projection: P) -> Result<()> {
+        let mut cache = self.cache.lock().map_err(|e| anyhow!(e.to_string()))?;
+        cache.put(aggregate_id.to_string(), (seq, projection));
+        Ok(())
+    }
+
+    fn evict(&self, aggregate_id: &str) -> Result<()> {
+        let mut cache = self.cache.lock().map_err(|e| anyhow!(e.to_string()))?;
+        cache.pop(aggregate_id);
+        Ok(())
+    }
+}
+
+/// Where the past events of an aggregate come from.
+#[derive(Debug, Clone)]
+enum EventSource {
+    /// The event store of AxonServer. Projections are cached.
+    EventStore(EventStoreClient<Channel>),
+    /// The events that were given to a test fixture.
+    Given(Arc<Vec<Event>>),
+}
+
+/// The context of a command handler: the projection of the aggregate and the events that the handler emits.
+pub struct AggregateContext<P: Projection, S: Settings = ()> {
+    aggregate_definition: Arc<AggregateDefinition<P, S>>,
+    event_source: EventSource,
+    events: Vec<(String, Box<dyn ApplicableTo<P, Event>>)>,
+    aggregate_id: Option<String>,
+    projection: P,
+    seq: i64,
+}
+
+impl<P: Projection, S: Settings> Debug for AggregateContext<P, S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AggregateContext")
+            .field("aggregate_definition", &self.aggregate_definition)
+            .field("aggregate_id", &self.aggregate_id)
+            .field("seq", &self.seq)
+            .field("events", &self.events)
+            .finish()
+    }
+}
+
+#[tonic::async_trait]
+impl<P: Projection, S: Settings> AggregateContextTrait<P> for AggregateContext<P, S> {
+    fn emit(&mut self, event_type: &str, event: Box<dyn ApplicableTo<P, Event>>) -> Result<()> {
+        self.events.push((event_type.to_string(), event));
+        Ok(())
+    }
+
+    async fn get_projection(&mut self, aggregate_id: &str) -> Result<P> {
+        if let Some(existing_aggregate_id) = &self.aggregate_id {
+            if aggregate_id != existing_aggregate_id {
+                return Err(anyhow!(
+                    "Inconsistent aggregate_id: {:?}: {:?}",
+                    aggregate_id,
+                    existing_aggregate_id
+                ));
+            }
+            return Ok(self.projection.clone());
+        }
+        self.aggregate_id = Some(aggregate_id.to_string());
+        let client = match &self.event_source {
+            EventSource::EventStore(client) => client.clone(),
+            EventSource::Given(events) => {
+                let events = events.clone();
+                self.replay(aggregate_id, &events).await?;
+                return Ok(self.projection.clone());
+            }
+        };
+        if let Some((seq, projection)) = self.aggregate_definition.cached(aggregate_id)? {
+            debug!("Cache hit: {:?}: {:?}", aggregate_id, seq);
+            self.seq = seq;
+            self.projection = projection;
+            return Ok(self.projection.clone());
+        }
+        debug!("Cache miss: {:?}", aggregate_id);
+        let allow_snapshots = self.aggregate_definition.snapshot_interval > 0;
+        if !self.restore(client.clone(), aggregate_id, allow_snapshots).await? {
+            debug!("Snapshot has a different revision, so replay all events: {:?}", aggregate_id);
+            self.restore(client, aggregate_id, false).await?;
+        }
+        debug!("Restored projection: {:?}: {:?}", self.seq, &self.projection);
+        if self.seq >= 0 {
+            self.aggregate_definition.cache(aggregate_id, self.seq, self.projection.clone())?;
+        }
+        Ok(self.projection.clone())
+    }
+}
+
+impl<P: Projection, S: Settings> AggregateContext<P, S> {
+    /// The settings that were given to function `create_aggregate_definition`.
+    pub fn settings(&self) -> &S {
+        &self.aggregate_definition.settings
+    }
+
+    /// The version of the aggregate: the sequence number of its last event, including the events that were emitted
+    /// by the command handler so far, or -1 if it has no events.
+    ///
+    /// Call function `get_projection` first.
+    pub fn version(&self) -> i64 {
+        self.seq + self.events.len() as i64
+    }
+
+    /// Fails with a conflict if a version is expected and the aggregate is at a different version.
+    ///
+    /// Call function `get_projection` first.
+    pub fn check_version(&self, expected_version: Option<i64>) -> Result<()> {
+        match expected_version {
+            Some(expected_version) if expected_version != self.version() => Err(DomainError::Conflict(format!(
+                "Expected version {}, but the aggregate is at version {}",
+                expected_version,
+                self.version()
+            ))
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fetches a single stored event of the aggregate.
+    pub async fn get_event(&mut self, aggregate_id: &str, seq: i64) -> Result<Option<Event>> {
+        let mut client = match &self.event_source {
+            EventSource::EventStore(client) => client.clone(),
+            EventSource::Given(events) => {
+                return Ok(events
+                    .iter()
+                    .find(|event| event.aggregate_identifier == aggregate_id && event.aggregate_sequence_number == seq)
+                    .cloned())
+            }
+        };
+        let request = GetAggregateEventsRequest {
+            aggregate_id: aggregate_id.to_string(),
+            allow_snapshots: false,
+            initial_sequence: seq,
+            max_sequence: seq,
+            min_token: 0,
+        };
+        let mut events = client.list_aggregate_events(request).await?.into_inner();
+        let event = events.message().await?;
+        Ok(event.filter(|event| event.aggregate_sequence_number == seq))
+    }
+
+    /// Restores the projection from the event store, starting from the latest snapshot if allowed.
+    ///
+    /// Returns `false` if the snapshot can't be used, because it was made with a different revision of the projection.
+    async fn restore(
+        &mut self,
+        mut client: EventStoreClient<Channel>,
+        aggregate_id: &str,
+        allow_snapshots: bool,
+    ) -> Result<bool> {
+        let request = GetAggregateEventsRequest {
+            aggregate_id: aggregate_id.to_string(),
+            allow_snapshots,
+            initial_sequence: 0,
+            max_sequence: i64::MAX,
+            min_token: 0,
+        };
+        let mut projection = (self.aggregate_definition.empty_projection)();
+        let mut seq = -1;
+        let mut events = client.list_aggregate_events(request).await?.into_inner();
+        while let Some(event) = events.message().await? {
+            let event_seq = event.aggregate_sequence_number;
+            if event.snapshot {
+                let payload = event.payload.unwrap_or_default();
+                if payload.revision != self.aggregate_definition.snapshot_revision {
+                    return Ok(false);
+                }
+                debug!("Restoring snapshot: {:?}: {:?}", aggregate_id, event_seq);
+                projection = P::decode(&*payload.data)?;
+            } else {
+                debug!("Replaying event: {:?}", Debuggable::from(&event));
+                projection = self.aggregate_definition.apply(event, projection).await?;
+            }
+            seq = event_seq;
+        }
+        self.projection = projection;
+        self.seq = seq;
+        Ok(true)
+    }
+
+    /// Restores the projection from the given events.
+    async fn replay(&mut self, aggregate_id: &str, events: &[Event]) -> Result<()> {
+        let mut projection = (self.aggregate_definition.empty_projection)();
+        self.seq = -1;
+        for event in events.iter().filter(|event| event.aggregate_identifier == aggregate_id) {
+            debug!("Replaying given event: {:?}", Debuggable::from(event));
+            self.seq = event.aggregate_sequence_number;
+            projection = self.aggregate_definition.apply(event.clone(), projection).await?;
+        }
+        self.projection = projection;
+        Ok(())
+    }
+}
+
+/// Subscribes to the commands of an aggregate, lets the command handlers verify them against the projection of the
+/// aggregate and sends emitted events to AxonServer.
+///
+/// The command stream is handled by the `command_worker` of dendrite. That worker only knows dendrite's own aggregates,
+/// so the commands are relayed through a dendrite aggregate with the same name and one handler per command. The relay
+/// handler leaves the dendrite projection alone: it looks up the aggregate of the command in `COMMAND_RELAYS` and
+/// passes the command to function `handle_command`, that restores the projection from the latest snapshot and stores
+/// the emitted events itself.
+pub async fn command_worker<P: Projection, S: Settings>(
+    axon_server_handle: AxonServerHandle,
+    aggregate_definition: Arc<AggregateDefinition<P, S>>,
+    worker_control: WorkerControl,
+) -> Result<()> {
+    debug!("Command worker: start: {:?}", worker_control.get_label());
+    let command_names = aggregate_definition.command_names.clone();
+    let relay: Arc<dyn CommandRelay> = Arc::new(AggregateRelay {
+        aggregate_definition: aggregate_definition.clone(),
+        event_store_client: EventStoreClient::new(axon_server_handle.conn.clone()),
+    });
+    let mut relay_registry: RelayHandlerRegistry = empty_handler_registry();
+    {
+        let mut relays = COMMAND_RELAYS.write().map_err(|e| anyhow!(e.to_string()))?;
+        for command_name in &command_names {
+            relays.insert(command_name.clone(), relay.clone());
+            relay_registry.insert_with_output(command_name, &raw_command, &relay_command)?;
+        }
+    }
+    let relay_definition = create_relay_definition(
+        aggregate_definition.projection_name.clone(),
+        Box::new(<()>::default as fn()),
+        relay_registry,
+        empty_handler_registry(),
+    );
+    let mut aggregate_registry = empty_aggregate_registry();
+    aggregate_registry.insert(Arc::new(Arc::new(relay_definition)))?;
+    let result = relay_command_worker(axon_server_handle, &mut aggregate_registry, worker_control).await;
+
+    let mut relays = COMMAND_RELAYS.write().map_err(|e| anyhow!(e.to_string()))?;
+    for command_name in &command_names {
+        if relays.get(command_name).map(|current| Arc::ptr_eq(current, &relay)) == Some(true) {
+            relays.remove(command_name);
+        }
+    }
+    result
+}
+
+lazy_static! {
+    /// The aggregates of the running command workers, by command name.
+    ///
+    /// Dendrite only accepts handlers that live forever, so the relay handler is a plain function that finds the
+    /// aggregate here. A restarted worker replaces the entries of its aggregate.
+    static ref COMMAND_RELAYS: RwLock<HashMap<String, Arc<dyn CommandRelay>>> = RwLock::new(HashMap::new());
+}
+
+/// Registry of the handlers of the dendrite aggregate that relays commands to an `AggregateDefinition`.
+type RelayHandlerRegistry = TheHandlerRegistry<Arc<Mutex<RelayContext<()>>>, Command, SerializedObject>;
+
+/// The future of a relay handler.
+type RelayFuture = Pin<Box<dyn Future<Output = Result<Option<SerializedObject>>> + Send>>;
+
+/// An aggregate that handles relayed commands, regardless of the types of its projection and settings.
+trait CommandRelay: Send + Sync {
+    fn relay(self: Arc<Self>, command: Command) -> RelayFuture;
+}
+
+/// What function `handle_command` needs to handle a relayed command.
+struct AggregateRelay<P: Projection, S: Settings> {
+    aggregate_definition: Arc<AggregateDefinition<P, S>>,
+    event_store_client: EventStoreClient<Channel>,
+}
+
+impl<P: Projection, S: Settings> CommandRelay for AggregateRelay<P, S> {
+    fn relay(self: Arc<Self>, command: Command) -> RelayFuture {
+        Box::pin(async move {
+            let mut event_store_client = self.event_store_client.clone();
+            handle_command(&self.aggregate_definition, &command, &mut event_store_client).await
+        })
+    }
+}
+
+/// The handler of the dendrite aggregate for every relayed command.
+fn relay_command(_data: Vec<u8>, command: Command, _context: Arc<Mutex<RelayContext<()>>>) -> RelayFuture {
+    let relay = COMMAND_RELAYS
+        .read()
+        .map_err(|e| anyhow!(e.to_string()))
+        .and_then(|relays| {
+            relays
+                .get(&command.name)
+                .cloned()
+                .ok_or_else(|| anyhow!("No aggregate for command: {:?}", command.name))
+        });
+    match relay {
+        Ok(relay) => relay.relay(command),
+        Err(e) => Box::pin(async move { Err(e) }),
+    }
+}
+
+/// Passes the payload of a command to a relay handler as is: function `handle_command` decodes it.
+fn raw_command(data: Bytes) -> Result<Vec<u8>, DecodeError> {
+    Ok(data.to_vec())
+}
+
+/// What a command handler did: its response, and the events that it emitted, ready to be stored.
+struct Execution<P> {
+    response: Option<SerializedObject>,
+    aggregate_id: Option<String>,
+    previous_seq: i64,
+    seq: i64,
+    projection: P,
+    events: Vec<Event>,
+}
+
+async fn handle_command<P: Projection, S: Settings>(
+    aggregate_definition: &Arc<AggregateDefinition<P, S>>,
+    command: &Command,
+    event_store_client: &mut EventStoreClient<Channel>,
+) -> Result<Option<SerializedObject>> {
+    debug!("Incoming command: {:?}", Debuggable::from(command));
+    let event_source = EventSource::EventStore(event_store_client.clone());
+    let Execution {
+        response,
+        aggregate_id,
+        previous_seq,
+        seq,
+        projection,
+        events,
+    } = execute_command(aggregate_definition, command, event_source).await?;
+    let aggregate_id = match aggregate_id {
+        Some(aggregate_id) if !events.is_empty() => aggregate_id,
+        _ => return Ok(response),
+    };
+
+    debug!("Store events: {:?}: {:?}", aggregate_id, events.len());
+    let timestamp = events.last().map(|event| event.timestamp).unwrap_or_default();
+    let request = Request::new(futures_util::stream::iter(events));
+    if let Err(e) = event_store_client.append_event(request).await {
+        aggregate_definition.evict(&aggregate_id)?;
+        return Err(e.into());
+    }
+    aggregate_definition.cache(&aggregate_id, seq, projection.clone())?;
+
+    if aggregate_definition.snapshot_due(previous_seq, seq) {
+        let snapshot = encode_snapshot(aggregate_definition, &aggregate_id, timestamp, seq, &projection)?;
+        let mut client = event_store_client.clone();
+        // The events are stored at this point, so the snapshot is only an optimisation: it is stored in the background
+        // and a failure is only logged. Without it, the projection is restored from an older snapshot and a few more
+        // events, and the next snapshot is taken after another `snapshots.interval` events.
+        tokio::spawn(async move {
+            debug!("Store snapshot: {:?}: {:?}", snapshot.aggregate_identifier, snapshot.aggregate_sequence_number);
+            if let Err(e) = client.append_snapshot(Request::new(snapshot)).await {
+                warn!("Could not store snapshot: {:?}", e);
+            }
+        });
+    }
+    Ok(response)
+}
+
+/// Lets the command handler handle the command, and applies the emitted events to the projection.
+async fn execute_command<P: Projection, S: Settings>(
+    aggregate_definition: &Arc<AggregateDefinition<P, S>>,
+    command: &Command,
+    event_source: EventSource,
+) -> Result<Execution<P>> {
+    let command_handler = aggregate_definition
+        .command_handler_registry
+        .get(&command.name)
+        .ok_or_else(|| anyhow!("Missing command handler: {:?}", command.name))?;
+    let data = command
+        .payload
+        .clone()
+        .map(|p| p.data)
+        .ok_or_else(|| anyhow!("No payload data for: {:?}", command.name))?;
+
+    let aggregate_context = Arc::new(Mutex::new(AggregateContext {
+        aggregate_definition: aggregate_definition.clone(),
+        event_source,
+        events: Vec::new(),
+        aggregate_id: None,
+        projection: (aggregate_definition.empty_projection)(),
+        seq: -1,
+    }));
+    let response = command_handler.handle(data, command.clone(), aggregate_context.clone()).await?;
+
+    let aggregate_context = aggregate_context.lock().await;
+    let mut execution = Execution {
+        response,
+        aggregate_id: aggregate_context.aggregate_id.clone(),
+        previous_seq: aggregate_context.seq,
+        seq: aggregate_context.seq,
+        projection: aggregate_context.projection.clone(),
+        events: Vec::new(),
+    };
+    if aggregate_context.events.is_empty() {
+        return Ok(execution);
+    }
+    let aggregate_id = execution
+        .aggregate_id
+        .clone()
+        .ok_or_else(|| anyhow!("Missing aggregate id"))?;
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
+    for (event_type, event) in &aggregate_context.events {
+        execution.seq += 1;
+        let event_message = encode_event(
+            event_type,
+            event.as_ref(),
+            &aggregate_definition.projection_name,
+            &aggregate_id,
+            command,
+            timestamp,
+            execution.seq,
+        )?;
+        execution.projection = aggregate_definition.apply(event_message.clone(), execution.projection).await?;
+        execution.events.push(event_message);
+    }
+    Ok(execution)
+}
+
+fn encode_event<P>(
+    event_type: &str,
+    event: &dyn ApplicableTo<P, Event>,
+    aggregate_name: &str,
+    aggregate_id: &str,
+    command: &Command,
+    timestamp: i64,
+    seq: i64,
+) -> Result<Event> {
+    let mut data = Vec::new();
+    event.encode_u8(&mut data)?;
+    let mut meta_data = HashMap::new();
+    meta_data.insert(COMMAND_NAME_KEY.to_string(), text_value(&command.name));
+    meta_data.insert(COMMAND_ID_KEY.to_string(), text_value(&command.message_identifier));
+    if let Some(correlation_id) = command.meta_data.get(CORRELATION_ID_KEY) {
+        meta_data.insert(CORRELATION_ID_KEY.to_string(), correlation_id.clone());
+    }
+    Ok(Event {
+        message_identifier: Uuid::new_v4().to_string(),
+        timestamp,
+        aggregate_identifier: aggregate_id.to_string(),
+        aggregate_sequence_number: seq,
+        aggregate_type: aggregate_name.to_string(),
+        payload: Some(SerializedObject {
+            r#type: event_type.to_string(),
+            revision: "".to_string(),
+            data,
+        }),
+        meta_data,
+        snapshot: false,
+    })
+}
+
+fn encode_snapshot<P: Projection, S: Settings>(
+    aggregate_definition: &AggregateDefinition<P, S>,
+    aggregate_id: &str,
+    timestamp: i64,
+    seq: i64,
+    projection: &P,
+) -> Result<Event> {
+    let mut data = Vec::new();
+    projection.encode(&mut data)?;
+    Ok(Event {
+        message_identifier: Uuid::new_v4().to_string(),
+        timestamp,
+        aggregate_identifier: aggregate_id.to_string(),
+        aggregate_sequence_number: seq,
+        aggregate_type: aggregate_definition.projection_name.clone(),
+        payload: Some(SerializedObject {
+            r#type: aggregate_definition.projection_name.clone(),
+            revision: aggregate_definition.snapshot_revision.clone(),
+            data,
+        }),
+        meta_data: HashMap::new(),
+        snapshot: true,
+    })
+}
+
+fn text_value(text: &str) -> MetaDataValue {
+    MetaDataValue {
+        data: Some(Data::TextValue(text.to_string())),
+    }
+}
+
+/// Runs the command handlers of an aggregate in-process.
+///
+/// Given a list of past events, that are applied through the sourcing handlers, when a command is dispatched through
+/// the command handler registry, then the test can assert on the emitted events and on the reply or the error. Nothing
+/// is stored and no connection to AxonServer is needed.
+pub struct AggregateTestFixture<P: Projection, S: Settings = ()> {
+    aggregate_definition: Arc<AggregateDefinition<P, S>>,
+    aggregate_id: String,
+    given: Vec<Event>,
+}
+
+/// The emitted events and the reply or the error of a command that was dispatched by an `AggregateTestFixture`.
+#[derive(Debug)]
+pub struct CommandOutcome {
+    pub events: Vec<Event>,
+    pub result: Result<Option<SerializedObject>>,
+}
+
+impl<P: Projection, S: Settings> AggregateTestFixture<P, S> {
+    /// Creates a fixture for the aggregate with the given identifier, without past events.
+    pub fn new(aggregate_definition: AggregateDefinition<P, S>, aggregate_id: &str) -> Self {
+        AggregateTestFixture {
+            aggregate_definition: Arc::new(aggregate_definition),
+            aggregate_id: aggregate_id.to_string(),
+            given: Vec::new(),
+        }
+    }
+
+    /// Adds a past event with timestamp zero.
+    pub fn given<E: Message>(self, event_type: &str, event: &E) -> Self {
+        self.given_at(0, event_type, event)
+    }
+
+    /// Adds a past event with the given timestamp in milliseconds since the epoch.
+    pub fn given_at<E: Message>(mut self, timestamp: i64, event_type: &str, event: &E) -> Self {
+        let event = Event {
+            message_identifier: Uuid::new_v4().to_string(),
+            timestamp,
+            aggregate_identifier: self.aggregate_id.clone(),
+            aggregate_sequence_number: self.given.len() as i64,
+            aggregate_type: self.aggregate_definition.projection_name.clone(),
+            payload: Some(SerializedObject {
+                r#type: event_type.to_string(),
+                revision: "".to_string(),
+                data: event.encode_to_vec(),
+            }),
+            meta_data: HashMap::new(),
+            snapshot: false,
+        };
+        self.given.push(event);
+        self
+    }
+
+    /// Dispatches a command through the command handler registry.
+    pub async fn when<C: Message>(&self, command_name: &str, command: &C) -> CommandOutcome {
+        let command = Command {
+            message_identifier: Uuid::new_v4().to_string(),
+            name: command_name.to_string(),
+            payload: Some(SerializedObject {
+                r#type: command_name.to_string(),
+                revision: "".to_string(),
+                data: command.encode_to_vec(),
+            }),
+            ..Default::default()
+        };
+        let event_source = EventSource::Given(Arc::new(self.given.clone()));
+        match execute_command(&self.aggregate_definition, &command, event_source).await {
+            Ok(execution) => CommandOutcome {
+                events: execution.events,
+                result: Ok(execution.response),
+            },
+            Err(e) => CommandOutcome {
+                events: Vec::new(),
+                result: Err(e),
+            },
+        }
+    }
+}
+
+impl CommandOutcome {
+    /// The types of the emitted events, in order.
+    pub fn event_types(&self) -> Vec<&str> {
+        self.events
+            .iter()
+            .filter_map(|event| event.payload.as_ref())
+            .map(|payload| payload.r#type.as_str())
+            .collect()
+    }
+
+    /// Decodes the emitted event at the given index.
+    pub fn event<T: Message + Default>(&self, index: usize) -> Result<T> {
+        let payload = self
+            .events
+            .get(index)
+            .and_then(|event| event.payload.as_ref())
+            .ok_or_else(|| anyhow!("No event at index: {}", index))?;
+        Ok(T::decode(&*payload.data)?)
+    }
+
+    /// Decodes the reply of the command handler; fails if the command handler failed.
+    pub fn reply<T: Message + Default>(&self) -> Result<Option<T>> {
+        match &self.result {
+            Ok(Some(reply)) => Ok(Some(T::decode(&*reply.data)?)),
+            Ok(None) => Ok(None),
+            Err(e) => Err(anyhow!("Command failed: {:?}", e)),
+        }
+    }
+
+    /// The error of the command handler, if it failed.
+    pub fn error(&self) -> Option<DomainError> {
+        self.result.as_ref().err().map(DomainError::from)
+    }
+}
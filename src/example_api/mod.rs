@@ -1,104 +1,191 @@
+use crate::example_auth::{authorize, Claims};
+use crate::example_config::ApplicationConfig;
+use crate::example_correlation::{request_context, send_query, submit_command};
+use crate::example_deadline::{request_deadline, within_deadline};
+use crate::example_error::DomainError;
+use crate::example_metrics::{observe_command, observe_rpc};
+use crate::example_shutdown::draining;
+use crate::example_tracing::traced_rpc;
 use crate::proto_example::greeter_service_server::GreeterService;
 use crate::proto_example::{
-    Acknowledgement, Empty, GreetCommand, GreetedEvent, Greeting, RecordCommand, SearchQuery,
-    SearchResponse, StopCommand,
+    Acknowledgement, Empty, GreetCommand, GreetedEvent, GreeterReference, GreeterVersion, Greeting, GreetingRetractedEvent, GreetingsMode,
+    GreetingsRequest, RecordCommand, RetractGreetingCommand, RetractRequest, SearchQuery, SearchResponse,
+    StopCommand,
 };
 use anyhow::{Error, Result};
 use bytes::Bytes;
-use dendrite::axon_utils::{
-    init_command_sender, query_events, AxonServerHandle, QuerySink, SubmitCommand,
-};
+use dendrite::axon_server::event::event_store_client::EventStoreClient;
+use dendrite::axon_server::event::{Event, EventWithToken, GetEventsRequest, GetLastTokenRequest};
+use dendrite::axon_utils::{query_events, wait_for_server, AxonServerHandle, SerializedObject};
 use dendrite::intellij_work_around::Debuggable;
 use futures_core::stream::Stream;
-use log::debug;
+use log::{debug, error};
 use prost::Message;
+use std::collections::HashSet;
 use std::fmt::Debug;
 use std::pin::Pin;
+use std::sync::Arc;
+use tokio::select;
 use tokio::sync::mpsc;
 use tonic::{Request, Response, Status};
 
+const FOLLOW_PERMITS_BATCH_SIZE: i64 = 3;
+
 /// Carries an `AxonServerHandle` and implements the `prost` generated `GreeterService`.
 ///
 /// The `AxonServerHandle` can be used to send commands and queries to AxonServer.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct GreeterServer {
     pub axon_server_handle: AxonServerHandle,
+    pub config: Arc<ApplicationConfig>,
 }
 
 #[tonic::async_trait]
 impl GreeterService for GreeterServer {
     async fn greet(&self, request: Request<Greeting>) -> Result<Response<Acknowledgement>, Status> {
-        let inner_request = request.into_inner();
-        debug!(
-            "Got a greet request: {:?}",
-            Debuggable::from(&inner_request)
-        );
-        let result_message = inner_request.message.clone();
-
-        let command = GreetCommand {
-            aggregate_identifier: "xxx".to_string(),
-            message: Some(inner_request),
-        };
+        let parent = request_context(&request);
+        observe_rpc("Greet", traced_rpc("GreeterService/Greet", parent, async move {
+            let claims = authorize(&request, "Greet")?;
+            let deadline = request_deadline(&request, "Greet", &self.config.deadlines)?;
+            let aggregate_identifier = greeter_identifier(claims, &request.get_ref().greeter_id)?;
+            let inner_request = request.into_inner();
+            debug!(
+                "Got a greet request: {:?}",
+                Debuggable::from(&inner_request)
+            );
+
+            let command = GreetCommand {
+                aggregate_identifier,
+                message: Some(Greeting {
+                    message: inner_request.message,
+                    ..Greeting::default()
+                }),
+                idempotency_key: inner_request.idempotency_key,
+                expected_version: inner_request.expected_version,
+            };
 
-        if let Some(serialized) = SubmitCommand::new("GreetCommand", Box::new(command))
-            .send(&self.axon_server_handle)
+            let serialized = observe_command(
+                "GreetCommand",
+                within_deadline(
+                    deadline,
+                    "GreetCommand",
+                    submit_command("GreetCommand", Box::new(command)).send(&self.axon_server_handle),
+                ),
+            )
             .await
-            .map_err(to_status)?
-        {
-            let reply_from_command_handler =
-                Message::decode(Bytes::from(serialized.data)).map_err(decode_error_to_status)?;
+            .map_err(to_status)?;
+
+            let reply: Acknowledgement = decode_reply(serialized)?;
+            debug!("Reply from command handler: {:?}", Debuggable::from(&reply));
+
+            Ok(Response::new(reply))
+        }))
+        .await
+    }
+
+    async fn record(&self, request: Request<GreeterReference>) -> Result<Response<GreeterVersion>, Status> {
+        let parent = request_context(&request);
+        observe_rpc("Record", traced_rpc("GreeterService/Record", parent, async move {
+            let claims = authorize(&request, "Record")?;
+            let deadline = request_deadline(&request, "Record", &self.config.deadlines)?;
+            let aggregate_identifier = greeter_identifier(claims, &request.get_ref().greeter_id)?;
+            let inner_request = request.into_inner();
             debug!(
-                "Reply from command handler: {:?}",
-                Debuggable::from(&reply_from_command_handler)
+                "Got a record request: {:?}",
+                Debuggable::from(&inner_request)
             );
-            return Ok(Response::new(reply_from_command_handler));
-        }
 
-        let default_reply = Acknowledgement {
-            message: format!("Hello {}!", result_message).into(),
-        };
+            let command = RecordCommand {
+                aggregate_identifier,
+                expected_version: inner_request.expected_version,
+            };
 
-        Ok(Response::new(default_reply))
+            let serialized = observe_command(
+                "RecordCommand",
+                within_deadline(
+                    deadline,
+                    "RecordCommand",
+                    submit_command("RecordCommand", Box::new(command)).send(&self.axon_server_handle),
+                ),
+            )
+            .await
+            .map_err(to_status)?;
+
+            let reply = decode_reply(serialized)?;
+
+            Ok(Response::new(reply))
+        }))
+        .await
     }
 
-    async fn record(&self, request: Request<Empty>) -> Result<Response<Empty>, Status> {
-        debug!(
-            "Got a record request: {:?}",
-            Debuggable::from(&request.into_inner())
-        );
+    async fn stop(&self, request: Request<GreeterReference>) -> Result<Response<GreeterVersion>, Status> {
+        let parent = request_context(&request);
+        observe_rpc("Stop", traced_rpc("GreeterService/Stop", parent, async move {
+            let claims = authorize(&request, "Stop")?;
+            let deadline = request_deadline(&request, "Stop", &self.config.deadlines)?;
+            let aggregate_identifier = greeter_identifier(claims, &request.get_ref().greeter_id)?;
+            let inner_request = request.into_inner();
+            debug!(
+                "Got a stop request: {:?}",
+                Debuggable::from(&inner_request)
+            );
 
-        let command = RecordCommand {
-            aggregate_identifier: "xxx".to_string(),
-        };
+            let command = StopCommand {
+                aggregate_identifier,
+                expected_version: inner_request.expected_version,
+            };
 
-        SubmitCommand::new("RecordCommand", Box::new(command))
-            .send(&self.axon_server_handle)
+            let serialized = observe_command(
+                "StopCommand",
+                within_deadline(
+                    deadline,
+                    "StopCommand",
+                    submit_command("StopCommand", Box::new(command)).send(&self.axon_server_handle),
+                ),
+            )
             .await
             .map_err(to_status)?;
 
-        let reply = Empty {};
+            let reply = decode_reply(serialized)?;
 
-        Ok(Response::new(reply))
+            Ok(Response::new(reply))
+        }))
+        .await
     }
 
-    async fn stop(&self, request: Request<Empty>) -> Result<Response<Empty>, Status> {
-        debug!(
-            "Got a stop request: {:?}",
-            Debuggable::from(&request.into_inner())
-        );
+    async fn retract(&self, request: Request<RetractRequest>) -> Result<Response<Empty>, Status> {
+        let parent = request_context(&request);
+        observe_rpc("Retract", traced_rpc("GreeterService/Retract", parent, async move {
+            let claims = authorize(&request, "Retract")?;
+            let deadline = request_deadline(&request, "Retract", &self.config.deadlines)?;
+            let aggregate_identifier = greeter_identifier(claims, &request.get_ref().greeter_id)?;
+            let inner_request = request.into_inner();
+            debug!(
+                "Got a retract request: {:?}",
+                Debuggable::from(&inner_request)
+            );
 
-        let command = StopCommand {
-            aggregate_identifier: "xxx".to_string(),
-        };
+            let command = RetractGreetingCommand {
+                aggregate_identifier,
+                sequence_number: inner_request.sequence_number,
+            };
 
-        SubmitCommand::new("StopCommand", Box::new(command))
-            .send(&self.axon_server_handle)
+            observe_command(
+                "RetractGreetingCommand",
+                within_deadline(
+                    deadline,
+                    "RetractGreetingCommand",
+                    submit_command("RetractGreetingCommand", Box::new(command)).send(&self.axon_server_handle),
+                ),
+            )
             .await
             .map_err(to_status)?;
 
-        let reply = Empty {};
+            let reply = Empty {};
 
-        Ok(Response::new(reply))
+            Ok(Response::new(reply))
+        }))
+        .await
     }
 
     type GreetingsStream =
@@ -106,45 +193,80 @@ impl GreeterService for GreeterServer {
 
     async fn greetings(
         &self,
-        _request: Request<Empty>,
+        request: Request<GreetingsRequest>,
     ) -> Result<Response<Self::GreetingsStream>, Status> {
-        let events = query_events(&self.axon_server_handle, "xxx")
-            .await
-            .map_err(to_status)?;
-        let (tx, mut rx): (
-            mpsc::Sender<Result<Greeting>>,
-            mpsc::Receiver<Result<Greeting>>,
-        ) = mpsc::channel(4);
-
-        tokio::spawn(async move {
-            for event in &events[..] {
-                if let Some(payload) = &event.payload {
-                    if payload.r#type == "GreetedEvent" {
-                        let greeted_event_message =
-                            GreetedEvent::decode(Bytes::from(payload.data.clone()))
-                                .ok()
-                                .map(|e| e.message);
-                        if let Some(greeting) = greeted_event_message.flatten() {
-                            debug!("Greeting: {:?}", greeting);
-                            tx.send(Ok(greeting)).await.ok();
+        let parent = request_context(&request);
+        observe_rpc("Greetings", traced_rpc("GreeterService/Greetings", parent, async move {
+            let claims = authorize(&request, "Greetings")?;
+            let aggregate_identifier = greeter_identifier(claims, &request.get_ref().greeter_id)?;
+            let mode = GreetingsMode::from_i32(request.get_ref().mode)
+                .ok_or_else(|| Status::invalid_argument("Unknown greetings mode"))?;
+            debug!("Greetings: {:?}: {:?}", aggregate_identifier, mode);
+
+            // Determine the position of the live tail before the replay, so no greeting falls in between.
+            let tracking_token = match mode {
+                GreetingsMode::ReplayOnly => None,
+                _ => Some(
+                    last_token(&self.axon_server_handle)
+                        .await
+                        .map_err(to_status)?
+                        + 1,
+                ),
+            };
+            let events = match mode {
+                GreetingsMode::LiveOnly => Vec::new(),
+                _ => query_events(&self.axon_server_handle, &aggregate_identifier)
+                    .await
+                    .map_err(to_status)?,
+            };
+            let (tx, mut rx): (
+                mpsc::Sender<Result<Greeting>>,
+                mpsc::Receiver<Result<Greeting>>,
+            ) = mpsc::channel(self.config.channels.greetings);
+
+            let axon_server_handle = self.axon_server_handle.clone();
+            tokio::spawn(async move {
+                let retracted = retracted_greetings(&events);
+                let mut last_sequence_number = -1;
+                for event in &events[..] {
+                    last_sequence_number = event.aggregate_sequence_number;
+                    if retracted.contains(&event.aggregate_sequence_number) {
+                        debug!("Skip retracted greeting: {:?}", event.aggregate_sequence_number);
+                        continue;
+                    }
+                    if let Some(greeting) = greeting_from_event(event) {
+                        debug!("Greeting: {:?}", greeting);
+                        if tx.send(Ok(greeting)).await.is_err() {
+                            debug!("Greetings stream closed during replay: {:?}", aggregate_identifier);
+                            return;
                         }
                     }
                 }
-            }
-            let greeting = Greeting {
-                message: "End of stream -oo-".to_string(),
-            };
-            debug!("End of stream: {:?}", Debuggable::from(&greeting));
-            tx.send(Ok(greeting)).await.ok();
-        });
+                if let Some(tracking_token) = tracking_token {
+                    if let Err(e) = follow_greetings(
+                        axon_server_handle,
+                        &aggregate_identifier,
+                        tracking_token,
+                        last_sequence_number,
+                        &tx,
+                    )
+                    .await
+                    {
+                        error!("Error while following greetings: {:?}", e);
+                    }
+                }
+                debug!("End of greetings stream: {:?}", aggregate_identifier);
+            });
 
-        let output = async_stream::try_stream! {
-            while let Some(Ok(value)) = rx.recv().await {
-                yield value as Greeting;
-            }
-        };
+            let output = async_stream::try_stream! {
+                while let Some(Ok(value)) = rx.recv().await {
+                    yield value as Greeting;
+                }
+            };
 
-        Ok(Response::new(Box::pin(output) as Self::GreetingsStream))
+            Ok(Response::new(Box::pin(output) as Self::GreetingsStream))
+        }))
+        .await
     }
 
     type SearchStream =
@@ -154,56 +276,201 @@ impl GreeterService for GreeterServer {
         &self,
         request: Request<SearchQuery>,
     ) -> Result<Response<Self::SearchStream>, Status> {
-        let (tx, mut rx): (
-            mpsc::Sender<Result<Greeting>>,
-            mpsc::Receiver<Result<Greeting>>,
-        ) = mpsc::channel(4);
-        let query = request.into_inner();
-        let query_response = self
-            .axon_server_handle
-            .send_query("SearchQuery", &query)
+        let parent = request_context(&request);
+        observe_rpc("Search", traced_rpc("GreeterService/Search", parent, async move {
+            authorize(&request, "Search")?;
+            let deadline = request_deadline(&request, "Search", &self.config.deadlines)?;
+            let (tx, mut rx): (
+                mpsc::Sender<Result<Greeting>>,
+                mpsc::Receiver<Result<Greeting>>,
+            ) = mpsc::channel(self.config.channels.search);
+            let query = request.into_inner();
+            let query_response = within_deadline(
+                deadline,
+                "SearchQuery",
+                send_query(&self.axon_server_handle, "SearchQuery", &query, deadline),
+            )
             .await
             .map_err(to_status)?;
 
-        tokio::spawn(async move {
-            for serialized_object in query_response {
-                if let Ok(search_response) =
-                    SearchResponse::decode(Bytes::from(serialized_object.data))
-                {
-                    debug!("Search response: {:?}", search_response);
-                    for greeting in search_response.greetings {
-                        debug!("Greeting: {:?}", greeting);
-                        tx.send(Ok(greeting)).await.ok();
+            tokio::spawn(async move {
+                for serialized_object in query_response {
+                    if let Ok(search_response) =
+                        SearchResponse::decode(Bytes::from(serialized_object.data))
+                    {
+                        debug!("Search response: {:?}", search_response);
+                        for greeting in search_response.greetings {
+                            debug!("Greeting: {:?}", greeting);
+                            tx.send(Ok(greeting)).await.ok();
+                        }
                     }
+                    debug!("Next!");
                 }
-                debug!("Next!");
-            }
-            debug!("Done!")
-        });
+                debug!("Done!")
+            });
 
-        let output = async_stream::try_stream! {
-            while let Some(Ok(value)) = rx.recv().await {
-                yield value as Greeting;
-            }
-        };
+            let output = async_stream::try_stream! {
+                while let Some(Ok(value)) = rx.recv().await {
+                    yield value as Greeting;
+                }
+            };
 
-        Ok(Response::new(Box::pin(output) as Self::SearchStream))
+            Ok(Response::new(Box::pin(output) as Self::SearchStream))
+        }))
+        .await
     }
 }
 
 /// Initialises a `GreeterServer`.
-pub async fn init() -> Result<GreeterServer> {
-    init_command_sender()
-        .await
-        .map(|command_sink| GreeterServer {
-            axon_server_handle: command_sink,
+///
+/// Polls AxonServer until it is available and ready.
+pub async fn init(config: Arc<ApplicationConfig>) -> Result<GreeterServer> {
+    let axon_server_config = &config.axon_server;
+    let axon_server_handle = wait_for_server(
+        &axon_server_config.host,
+        axon_server_config.port,
+        &axon_server_config.label,
+    )
+    .await?;
+    debug!("Axon connection: {:?}", axon_server_handle);
+    Ok(GreeterServer {
+        axon_server_handle,
+        config,
+    })
+}
+
+/// Sends the greetings of a single greeter to `tx` as soon as they are committed to the event store.
+///
+/// Stops when the receiving end of `tx` is dropped, _i.e._, when the client cancels the call, or when the application
+/// shuts down.
+///
+/// Each follower opens its own event stream over the events of all aggregates and skips the events of other greeters,
+/// so every follower costs a stream from AxonServer and reads the full event traffic.
+async fn follow_greetings(
+    axon_server_handle: AxonServerHandle,
+    aggregate_identifier: &str,
+    tracking_token: i64,
+    mut last_sequence_number: i64,
+    tx: &mpsc::Sender<Result<Greeting>>,
+) -> Result<()> {
+    let mut client = EventStoreClient::new(axon_server_handle.conn.clone());
+    let request = GetEventsRequest {
+        tracking_token,
+        number_of_permits: FOLLOW_PERMITS_BATCH_SIZE * 2,
+        client_id: axon_server_handle.client_id.clone(),
+        component_name: axon_server_handle.display_name.clone(),
+        processor: format!("Greetings follower: {:?}", aggregate_identifier),
+        blacklist: Vec::new(),
+        force_read_from_leader: false,
+    };
+    let (permits_tx, mut permits_rx) = mpsc::channel(2);
+    let outbound = async_stream::stream! {
+        yield request.clone();
+        while let Some(number_of_permits) = permits_rx.recv().await {
+            yield GetEventsRequest {
+                number_of_permits,
+                ..request.clone()
+            };
+        }
+    };
+    let mut events = client.list_events(outbound).await?.into_inner();
+    let mut permits = FOLLOW_PERMITS_BATCH_SIZE * 2;
+    loop {
+        let event_with_token = select! {
+            _closed = tx.closed() => return Ok(()),
+            _draining = draining() => return Ok(()),
+            event_with_token = events.message() => event_with_token?,
+        };
+        let event = match event_with_token {
+            Some(EventWithToken {
+                event: Some(event), ..
+            }) => event,
+            Some(_) => continue,
+            None => return Ok(()),
+        };
+        permits -= 1;
+        if permits <= FOLLOW_PERMITS_BATCH_SIZE {
+            permits_tx.send(FOLLOW_PERMITS_BATCH_SIZE).await?;
+            permits += FOLLOW_PERMITS_BATCH_SIZE;
+        }
+        if event.aggregate_identifier != aggregate_identifier
+            || event.aggregate_sequence_number <= last_sequence_number
+        {
+            continue;
+        }
+        last_sequence_number = event.aggregate_sequence_number;
+        if let Some(greeting) = greeting_from_event(&event) {
+            debug!("Live greeting: {:?}", greeting);
+            if tx.send(Ok(greeting)).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn last_token(axon_server_handle: &AxonServerHandle) -> Result<i64> {
+    let mut client = EventStoreClient::new(axon_server_handle.conn.clone());
+    let response = client.get_last_token(GetLastTokenRequest {}).await?;
+    Ok(response.into_inner().token)
+}
+
+/// The greeting of a `GreetedEvent`, with the sequence number of the event that identifies it for `Retract`.
+fn greeting_from_event(event: &Event) -> Option<Greeting> {
+    let payload = event.payload.as_ref()?;
+    if payload.r#type != "GreetedEvent" {
+        return None;
+    }
+    GreetedEvent::decode(Bytes::from(payload.data.clone()))
+        .ok()
+        .and_then(|e| e.message)
+        .map(|greeting| Greeting {
+            sequence_number: event.aggregate_sequence_number,
+            ..greeting
         })
 }
 
+/// The sequence numbers of the greetings that were retracted by a `GreetingRetractedEvent` among the given events.
+fn retracted_greetings(events: &[Event]) -> HashSet<i64> {
+    events
+        .iter()
+        .filter_map(|event| event.payload.as_ref())
+        .filter(|payload| payload.r#type == "GreetingRetractedEvent")
+        .filter_map(|payload| GreetingRetractedEvent::decode(Bytes::from(payload.data.clone())).ok())
+        .map(|event| event.sequence_number)
+        .collect()
+}
+
+/// Determines the identifier of the `GreeterProjection` aggregate that a request applies to.
+///
+/// The subject of the verified JWT identifies the caller. An explicit identifier on the request is only accepted if it
+/// is the subject itself, or if the caller has role `admin`.
+fn greeter_identifier(claims: &Claims, explicit_identifier: &str) -> Result<String, Status> {
+    let subject = claims.subject();
+    if explicit_identifier.is_empty() {
+        return subject
+            .map(str::to_string)
+            .ok_or_else(|| Status::invalid_argument("Missing greeter identifier"));
+    }
+    if subject == Some(explicit_identifier) || claims.has_role("admin") {
+        return Ok(explicit_identifier.to_string());
+    }
+    Err(Status::permission_denied(format!(
+        "Not allowed to act on greeter: {}",
+        explicit_identifier
+    )))
+}
+
 fn to_status(e: Error) -> Status {
-    Status::unknown(e.to_string())
+    DomainError::from(&e).to_status()
+}
+
+/// Decodes the reply of a command handler. The command handlers of the greeter always reply, so a missing reply is
+/// an internal error rather than an empty reply with version zero.
+fn decode_reply<T: Message + Default>(serialized: Option<SerializedObject>) -> Result<T, Status> {
+    let serialized = serialized.ok_or_else(|| DomainError::Internal("Missing reply from command handler".to_string()).to_status())?;
+    T::decode(Bytes::from(serialized.data)).map_err(decode_error_to_status)
 }
 
 fn decode_error_to_status(e: prost::DecodeError) -> Status {
-    Status::unknown(e.to_string())
+    DomainError::from(e).to_status()
 }
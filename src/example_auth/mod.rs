@@ -0,0 +1,213 @@
+use dendrite::auth as dendrite_auth;
+use anyhow::anyhow;
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tonic::{Request, Status};
+use x509_parser::parse_x509_certificate;
+
+/// Roles that are required for each RPC that is subject to role-based access control.
+///
+/// RPCs that are not listed here are denied by function `authorize`.
+const REQUIRED_ROLES: &[(&str, &str)] = &[
+    ("Greet", "user"),
+    ("Record", "admin"),
+    ("Stop", "admin"),
+    ("Retract", "user"),
+    ("Greetings", "user"),
+    ("Search", "user"),
+    ("SetProperty", "admin"),
+];
+
+lazy_static! {
+    static ref CERTIFICATE_ROLES: RwLock<BTreeMap<String, Vec<String>>> = RwLock::new(BTreeMap::new());
+}
+
+/// The verified claims of the JWT that was presented by the caller.
+///
+/// The interceptor stores these in the extensions of the request.
+#[derive(Debug, Clone)]
+pub struct Claims {
+    claims: HashMap<String, Value>,
+}
+
+impl Claims {
+    /// The subject (`sub`) of the JWT, if any.
+    pub fn subject(&self) -> Option<&str> {
+        self.claims.get("sub").and_then(Value::as_str)
+    }
+
+    /// The roles that were granted to the subject.
+    ///
+    /// Claim `roles` can either be an array of strings or a single string of comma or space separated roles.
+    pub fn roles(&self) -> Vec<&str> {
+        match self.claims.get("roles") {
+            Some(Value::Array(roles)) => roles.iter().filter_map(Value::as_str).collect(),
+            Some(Value::String(roles)) => roles
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|role| !role.is_empty())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Returns `true` if the subject was granted the given role.
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles().contains(&role)
+    }
+
+    /// The raw claims.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.claims.get(key)
+    }
+}
+
+/// The subject of the verified TLS client certificate that was presented by the caller.
+///
+/// The interceptor stores this in the extensions of the request.
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    subject: String,
+}
+
+impl ClientCertificate {
+    /// The distinguished name of the subject, _e.g._, `CN=client, O=Example`.
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+}
+
+/// Sets the roles that are granted to the subjects of client certificates.
+pub fn set_certificate_roles(roles: BTreeMap<String, Vec<String>>) -> anyhow::Result<()> {
+    let mut certificate_roles = CERTIFICATE_ROLES
+        .write()
+        .map_err(|e| anyhow!("Can't set certificate roles: {:?}", e))?;
+    *certificate_roles = roles;
+    Ok(())
+}
+
+/// Interceptor that rejects requests that carry neither a valid JWT nor a verified client certificate.
+///
+/// The verified claims are added to the extensions of the request.
+pub fn authenticate(request: Request<()>) -> Result<Request<()>, Status> {
+    if request.metadata().get("authorization").is_none() && request.peer_certs().is_none() {
+        return Err(Status::unauthenticated("Missing authorization header"));
+    }
+    authenticate_if_present(request)
+}
+
+/// Interceptor that accepts requests without a JWT, but rejects requests that carry an invalid JWT.
+///
+/// Used for services that have some RPCs that are open to anonymous callers. Those RPCs that are not,
+/// must call function `authorize`.
+///
+/// The subject of a verified client certificate is added as a `ClientCertificate`. If there is no JWT, then the
+/// claims consist of that subject and the roles that were configured for it.
+pub fn authenticate_if_present(mut request: Request<()>) -> Result<Request<()>, Status> {
+    let client_certificate = client_certificate(&request)?;
+    if let Some(client_certificate) = &client_certificate {
+        request.extensions_mut().insert(client_certificate.clone());
+    }
+    let token = match request.metadata().get("authorization") {
+        Some(token) => token
+            .to_str()
+            .map_err(|_| Status::unauthenticated("Malformed authorization header"))?,
+        None => {
+            if let Some(client_certificate) = client_certificate {
+                let claims = certificate_claims(client_certificate.subject)?;
+                debug!("Certificate credentials: [{:?}]", claims);
+                request.extensions_mut().insert(claims);
+            }
+            return Ok(request);
+        }
+    };
+    let token = token.strip_prefix("Bearer ").unwrap_or(token);
+    let claims = verify_token(token)?;
+    debug!("Credentials: [{:?}]", claims);
+    request.extensions_mut().insert(claims);
+    Ok(request)
+}
+
+/// Verifies that the caller was granted the role that is required for the given RPC.
+///
+/// Returns the verified claims of the caller.
+pub fn authorize<'a, T>(request: &'a Request<T>, method: &str) -> Result<&'a Claims, Status> {
+    let claims = request
+        .extensions()
+        .get::<Claims>()
+        .ok_or_else(|| Status::unauthenticated("Missing credentials"))?;
+    let required_role = REQUIRED_ROLES
+        .iter()
+        .find(|(name, _)| *name == method)
+        .map(|(_, role)| *role)
+        .ok_or_else(|| Status::permission_denied(format!("No access policy for: {}", method)))?;
+    if !claims.has_role(required_role) {
+        warn!(
+            "Permission denied: {:?}: {:?}: missing role: {:?}",
+            method,
+            claims.subject(),
+            required_role
+        );
+        return Err(Status::permission_denied(format!(
+            "Missing role: {}",
+            required_role
+        )));
+    }
+    Ok(claims)
+}
+
+fn client_certificate<T>(request: &Request<T>) -> Result<Option<ClientCertificate>, Status> {
+    let certificates = match request.peer_certs() {
+        Some(certificates) => certificates,
+        None => return Ok(None),
+    };
+    let certificate = match certificates.first() {
+        Some(certificate) => certificate,
+        None => return Ok(None),
+    };
+    let (_, parsed) = parse_x509_certificate(certificate.get_ref()).map_err(|e| {
+        warn!("Client certificate parsing error: {:?}", e);
+        Status::unauthenticated("Invalid client certificate")
+    })?;
+    Ok(Some(ClientCertificate {
+        subject: parsed.subject().to_string(),
+    }))
+}
+
+fn certificate_claims(subject: String) -> Result<Claims, Status> {
+    let roles = CERTIFICATE_ROLES
+        .read()
+        .map_err(|e| Status::internal(format!("Can't read certificate roles: {:?}", e)))?
+        .get(&subject)
+        .cloned()
+        .unwrap_or_default();
+    let mut claims = HashMap::new();
+    claims.insert("sub".to_string(), Value::from(subject));
+    claims.insert("roles".to_string(), Value::from(roles));
+    Ok(Claims { claims })
+}
+
+fn verify_token(token: &str) -> Result<Claims, Status> {
+    let claims = dendrite_auth::verify_jwt(token).map_err(|e| {
+        warn!("JWT parsing error: {:?}", e);
+        Status::unauthenticated("Invalid token")
+    })?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| Status::internal(e.to_string()))?
+        .as_secs();
+    if let Some(expiration) = claims.get("exp").and_then(Value::as_u64) {
+        if expiration <= now {
+            return Err(Status::unauthenticated("Token expired"));
+        }
+    }
+    if let Some(not_before) = claims.get("nbf").and_then(Value::as_u64) {
+        if not_before > now {
+            return Err(Status::unauthenticated("Token not yet valid"));
+        }
+    }
+    Ok(Claims { claims })
+}
@@ -1,133 +1,364 @@
+use crate::example_aggregate::{
+    command_worker, create_aggregate_definition, AggregateContext, AggregateDefinition, CommandHandlerRegistry,
+};
+use crate::example_config::{ApplicationConfig, SnapshotConfig};
+use crate::example_correlation::command_context;
+use crate::example_error::DomainError;
+use crate::example_health::{report, Component};
+use crate::example_logging::with_message;
+use crate::example_supervisor::supervise;
+use crate::example_tracing::child_context;
 use crate::proto_example::{
-    Acknowledgement, Empty, GreetCommand, GreetedEvent, GreeterProjection, RecordCommand,
-    StartedRecordingEvent, StopCommand, StoppedRecordingEvent,
+    Acknowledgement, Empty, GreetCommand, GreetedEvent, GreeterProjection, GreeterVersion, GreetingQuota, GreetingReceipt,
+    GreetingRetractedEvent, QuotaAssignedEvent, RecordCommand, RetractGreetingCommand, StartedRecordingEvent, StopCommand, StoppedRecordingEvent,
 };
-use anyhow::{anyhow, Context, Result};
-use async_lock::Mutex;
+use anyhow::{Context, Result};
 use dendrite::axon_server::command::Command;
-use dendrite::axon_utils::{command_worker, create_aggregate_definition, empty_aggregate_registry, empty_handler_registry, AggregateContext, AggregateContextTrait, AggregateDefinition, AggregateRegistry, ApplicableTo, AxonServerHandle, HandlerRegistry, SerializedObject, TheHandlerRegistry, WorkerControl};
+use dendrite::axon_server::event::Event;
+use dendrite::axon_utils::{empty_handler_registry, AggregateContextTrait, ApplicableTo, AxonServerHandle, HandlerRegistry, SerializedObject, WorkerControl, WorkerThread};
 use dendrite::intellij_work_around::Debuggable;
 use dendrite::macros as dendrite_macros;
-use log::{debug, error};
+use log::debug;
+use opentelemetry::trace::{FutureExt, SpanKind};
 use prost::Message;
+use std::convert::TryFrom;
 use std::ops::Deref;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-/// Handles commands.
-///
-/// Constructs an aggregate registry and delegates to function `command_worker`.
-pub async fn handle_commands(axon_server_handle: AxonServerHandle, worker_control: WorkerControl) {
-    if let Err(e) = internal_handle_commands(axon_server_handle, worker_control).await {
-        error!("Error while handling commands: {:?}", e);
+/// The revision of `GreeterProjection` in snapshots.
+const PROJECTION_REVISION: &str = "3";
+
+/// The configured settings of the greeter aggregate. They only apply to new events: the idempotency window is recorded
+/// in each `GreetedEvent` and the quota in the `QuotaAssignedEvent` of a greeter.
+#[derive(Debug, Clone)]
+struct GreeterSettings {
+    /// How long the acknowledgement of a greeting is kept for repeated requests with the same idempotency key.
+    idempotency_window_millis: i64,
+    /// The quota that is assigned to a greeter with its first greeting.
+    quota: GreetingQuota,
+}
+
+impl GreeterSettings {
+    fn from_config(config: &ApplicationConfig) -> Result<Self> {
+        Ok(GreeterSettings {
+            idempotency_window_millis: config.idempotency.window_millis()?,
+            quota: GreetingQuota {
+                max_greetings: i64::try_from(config.quota.max_greetings)?,
+                min_interval_millis: i64::try_from(config.quota.min_interval_millis)?,
+            },
+        })
     }
-    debug!("Stopped handling commands");
 }
 
-async fn internal_handle_commands(axon_server_handle: AxonServerHandle, worker_control: WorkerControl) -> Result<()> {
-    debug!("Handle commands: {:?}", worker_control.get_label());
+/// The context of the command handlers of the greeter aggregate.
+type GreeterContext = AggregateContext<GreeterProjection, GreeterSettings>;
+
+/// Creates a supervised worker that handles commands with the given configuration.
+pub fn handle_commands_with(config: Arc<ApplicationConfig>) -> WorkerThread {
+    let worker_config = config.clone();
+    supervise("command", &config, move |handle, worker_control| {
+        Box::pin(internal_handle_commands(handle, worker_config.clone(), worker_control))
+    })
+}
+
+/// Handles commands.
+///
+/// Constructs an aggregate definition that takes snapshots as configured, and delegates to function `command_worker`.
+async fn internal_handle_commands(axon_server_handle: AxonServerHandle, config: Arc<ApplicationConfig>, worker_control: WorkerControl) -> Result<()> {
+    debug!("Handle commands: {:?}: {:?}", worker_control.get_label(), config.workers.command);
     debug!("Axon server handle: {:?}", &axon_server_handle);
 
+    let aggregate_definition = greeter_aggregate(GreeterSettings::from_config(&config)?, &config.snapshots)?;
+
+    report(Component::CommandHandler, true).await;
+    let result = command_worker(axon_server_handle, Arc::new(aggregate_definition), worker_control)
+        .await
+        .context("Error while handling commands");
+    report(Component::CommandHandler, false).await;
+    result
+}
+
+/// Creates the definition of the greeter aggregate: its command handlers and sourcing handlers.
+fn greeter_aggregate(
+    settings: GreeterSettings,
+    snapshot_config: &SnapshotConfig,
+) -> Result<AggregateDefinition<GreeterProjection, GreeterSettings>> {
     let mut sourcing_handler_registry = empty_handler_registry();
-    let mut command_handler_registry: TheHandlerRegistry<
-        Arc<Mutex<AggregateContext<GreeterProjection>>>,
-        Command,
-        SerializedObject,
-    > = empty_handler_registry();
+    let mut command_handler_registry: CommandHandlerRegistry<GreeterProjection, GreeterSettings> = empty_handler_registry();
 
     command_handler_registry.register(&handle_greet_command)?;
     command_handler_registry.register(&handle_record_command)?;
     command_handler_registry.register(&handle_stop_command)?;
+    command_handler_registry.register(&handle_retract_greeting_command)?;
 
     sourcing_handler_registry.register(&handle_greeted_source_event)?;
+    sourcing_handler_registry.register(&handle_quota_assigned_source_event)?;
+    sourcing_handler_registry.register(&handle_greeting_retracted_source_event)?;
     sourcing_handler_registry.register(&handle_started_recording_source_event)?;
     sourcing_handler_registry.register(&handle_stopped_recording_source_event)?;
 
-    let aggregate_definition: AggregateDefinition<GreeterProjection> = create_aggregate_definition(
-        "GreeterProjection".to_string(),
-        Box::from(empty_projection as fn() -> GreeterProjection),
+    create_aggregate_definition(
+        "GreeterProjection",
+        PROJECTION_REVISION,
+        &["GreetCommand", "RecordCommand", "StopCommand", "RetractGreetingCommand"],
+        empty_projection,
         command_handler_registry,
         sourcing_handler_registry,
-    );
+        settings,
+        snapshot_config,
+    )
+}
 
-    let mut aggregate_registry = empty_aggregate_registry();
-    aggregate_registry.insert(Arc::new(Arc::new(aggregate_definition)))?;
+fn empty_projection() -> GreeterProjection {
+    GreeterProjection {
+        is_recording: true,
+        ..Default::default()
+    }
+}
 
-    command_worker(axon_server_handle, &mut aggregate_registry, worker_control)
-        .await
-        .context("Error while handling commands")
+/// Checks the greeting against the previous greeting and the quota of the greeter.
+fn check_quota(message: &str, projection: &GreeterProjection, quota: &GreetingQuota, now: i64) -> Result<()> {
+    if projection.greeting_count == 0 {
+        return Ok(());
+    }
+    if projection.last_greeting == message {
+        return Err(DomainError::DuplicateGreeting(format!("Same as the previous greeting: {:?}", message)).into());
+    }
+    if quota.max_greetings > 0 && projection.greeting_count >= quota.max_greetings {
+        return Err(DomainError::QuotaExceeded(format!("Maximum number of greetings reached: {}", quota.max_greetings)).into());
+    }
+    let elapsed = now - projection.last_greeting_timestamp;
+    if elapsed < quota.min_interval_millis {
+        return Err(DomainError::GreetingTooSoon(format!(
+            "Minimum interval between greetings is {}ms, only {}ms passed",
+            quota.min_interval_millis, elapsed
+        ))
+        .into());
+    }
+    Ok(())
 }
 
-fn empty_projection() -> GreeterProjection {
-    let mut projection = GreeterProjection::default();
-    projection.is_recording = true;
-    projection
+fn acknowledgement(message: &str) -> String {
+    format!("ACK! {}", message)
+}
+
+fn now_millis() -> Result<i64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64)
 }
 
 #[dendrite_macros::command_handler]
 async fn handle_greet_command(
     command: GreetCommand,
-    aggregate_context: &mut AggregateContext<GreeterProjection>,
+    aggregate_context: &mut GreeterContext,
+    command_message: Command,
 ) -> Result<Option<Acknowledgement>> {
-    let message = command
-        .message
-        .as_ref()
-        .map(|g| &*g.message)
-        .unwrap_or("-/-");
-    if message == "ERROR" {
-        return Err(anyhow!("Panicked at reading 'ERROR'"));
-    }
+    let context = with_message(
+        &child_context("handle_greet_command", SpanKind::Consumer, &command_context(&command_message)),
+        "GreetCommand",
+        Some(&command.aggregate_identifier),
+    );
+    async move {
+        let message = command
+            .message
+            .as_ref()
+            .map(|g| &*g.message)
+            .unwrap_or("-/-");
+        if message == "ERROR" {
+            return Err(DomainError::InvalidArgument("Panicked at reading 'ERROR'".to_string()).into());
+        }
 
-    let projection = aggregate_context.get_projection("xxx").await?;
-    if !projection.is_recording {
-        debug!("Not recording, so no events emitted nor acknowledgement returned");
-        return Ok(None);
-    }
-    debug!("Recording, so proceed");
+        let projection = aggregate_context.get_projection(&command.aggregate_identifier).await?;
+        let now = now_millis()?;
+        if !command.idempotency_key.is_empty() {
+            if let Some(receipt) = projection
+                .recent_greetings
+                .iter()
+                .find(|receipt| receipt.idempotency_key == command.idempotency_key && receipt.expires_at > now)
+            {
+                debug!("Repeated greeting: {:?}", command.idempotency_key);
+                return Ok(Some(Acknowledgement {
+                    message: receipt.acknowledgement.clone(),
+                    version: aggregate_context.version(),
+                }));
+            }
+        }
+        aggregate_context.check_version(command.expected_version)?;
+
+        if !projection.is_recording {
+            debug!("Not recording, so no events emitted");
+            return Ok(Some(Acknowledgement {
+                message: format!("Hello {}!", message),
+                version: aggregate_context.version(),
+            }));
+        }
+        debug!("Recording, so proceed");
 
-    let greeting = command.message.clone();
-    aggregate_context.emit("GreetedEvent", Box::new(GreetedEvent { message: greeting }))?;
+        let quota = match projection.quota.as_ref() {
+            Some(quota) => quota.clone(),
+            None => {
+                let quota = aggregate_context.settings().quota.clone();
+                aggregate_context.emit(
+                    "QuotaAssignedEvent",
+                    Box::new(QuotaAssignedEvent {
+                        quota: Some(quota.clone()),
+                    }),
+                )?;
+                quota
+            }
+        };
+        check_quota(message, &projection, &quota, now)?;
 
-    Ok(Some(Acknowledgement {
-        message: format!("ACK! {}", message),
-    }))
+        let greeting = command.message.clone();
+        let idempotency_window_millis = aggregate_context.settings().idempotency_window_millis;
+        aggregate_context.emit(
+            "GreetedEvent",
+            Box::new(GreetedEvent {
+                message: greeting,
+                idempotency_key: command.idempotency_key.clone(),
+                idempotency_window_millis,
+            }),
+        )?;
+
+        Ok(Some(Acknowledgement {
+            message: acknowledgement(message),
+            version: aggregate_context.version(),
+        }))
+    }
+    .with_context(context)
+    .await
 }
 
 #[dendrite_macros::command_handler]
 async fn handle_record_command(
     command: RecordCommand,
-    aggregate_context: &mut AggregateContext<GreeterProjection>,
-) -> Result<Option<Empty>> {
-    let projection = aggregate_context.get_projection("xxx").await?;
-    debug!("Record command handler: {:?}", Debuggable::from(&command));
-    if projection.is_recording {
-        debug!("Unnecessary RecordCommand");
-        return Ok(None);
+    aggregate_context: &mut GreeterContext,
+    command_message: Command,
+) -> Result<Option<GreeterVersion>> {
+    let context = with_message(
+        &child_context("handle_record_command", SpanKind::Consumer, &command_context(&command_message)),
+        "RecordCommand",
+        Some(&command.aggregate_identifier),
+    );
+    async move {
+        let projection = aggregate_context.get_projection(&command.aggregate_identifier).await?;
+        debug!("Record command handler: {:?}", Debuggable::from(&command));
+        aggregate_context.check_version(command.expected_version)?;
+        if projection.is_recording {
+            debug!("Unnecessary RecordCommand");
+        } else {
+            aggregate_context.emit("StartedRecordingEvent", Box::new(StartedRecordingEvent {}))?;
+        }
+        Ok(Some(GreeterVersion {
+            version: aggregate_context.version(),
+        }))
     }
-    aggregate_context.emit("StartedRecordingEvent", Box::new(StartedRecordingEvent {}))?;
-    Ok(Some(Empty::default()))
+    .with_context(context)
+    .await
 }
 
 #[dendrite_macros::command_handler]
 async fn handle_stop_command(
     command: StopCommand,
-    aggregate_context: &mut AggregateContext<GreeterProjection>,
+    aggregate_context: &mut GreeterContext,
+    command_message: Command,
+) -> Result<Option<GreeterVersion>> {
+    let context = with_message(
+        &child_context("handle_stop_command", SpanKind::Consumer, &command_context(&command_message)),
+        "StopCommand",
+        Some(&command.aggregate_identifier),
+    );
+    async move {
+        let projection = aggregate_context.get_projection(&command.aggregate_identifier).await?;
+        debug!("Stop command handler: {:?}", Debuggable::from(&command));
+        aggregate_context.check_version(command.expected_version)?;
+        if !projection.is_recording {
+            debug!("Unnecessary StopCommand");
+        } else {
+            aggregate_context.emit("StoppedRecordingEvent", Box::new(StoppedRecordingEvent {}))?;
+        }
+        Ok(Some(GreeterVersion {
+            version: aggregate_context.version(),
+        }))
+    }
+    .with_context(context)
+    .await
+}
+
+#[dendrite_macros::command_handler]
+async fn handle_retract_greeting_command(
+    command: RetractGreetingCommand,
+    aggregate_context: &mut GreeterContext,
+    command_message: Command,
 ) -> Result<Option<Empty>> {
-    let projection = aggregate_context.get_projection("xxx").await?;
-    debug!("Stop command handler: {:?}", Debuggable::from(&command));
-    if !projection.is_recording {
-        debug!("Unnecessary StopCommand");
-        return Ok(None);
+    let context = with_message(
+        &child_context("handle_retract_greeting_command", SpanKind::Consumer, &command_context(&command_message)),
+        "RetractGreetingCommand",
+        Some(&command.aggregate_identifier),
+    );
+    async move {
+        let sequence_number = command.sequence_number;
+        if sequence_number < 0 {
+            return Err(DomainError::InvalidArgument(format!("Invalid sequence number: {}", sequence_number)).into());
+        }
+        let projection = aggregate_context.get_projection(&command.aggregate_identifier).await?;
+        debug!("Retract greeting command handler: {:?}", Debuggable::from(&command));
+        if projection.retracted_greetings.contains(&sequence_number) {
+            debug!("Unnecessary RetractGreetingCommand");
+            return Ok(None);
+        }
+        let greeted_event = aggregate_context
+            .get_event(&command.aggregate_identifier, sequence_number)
+            .await?
+            .and_then(|event| event.payload)
+            .filter(|payload| payload.r#type == "GreetedEvent")
+            .map(|payload| GreetedEvent::decode(&*payload.data))
+            .transpose()?
+            .ok_or_else(|| DomainError::FailedPrecondition(format!("No greeting with sequence number: {}", sequence_number)))?;
+        aggregate_context.emit(
+            "GreetingRetractedEvent",
+            Box::new(GreetingRetractedEvent {
+                sequence_number,
+                message: greeted_event.message,
+            }),
+        )?;
+        Ok(Some(Empty::default()))
     }
-    aggregate_context.emit("StoppedRecordingEvent", Box::new(StoppedRecordingEvent {}))?;
-    Ok(Some(Empty::default()))
+    .with_context(context)
+    .await
 }
 
 #[dendrite_macros::event_sourcing_handler]
-fn handle_greeted_source_event(_event: GreetedEvent, projection: GreeterProjection) {
+fn handle_greeted_source_event(event: GreetedEvent, mut projection: GreeterProjection, event_message: Event) {
     debug!(
         "Apply greeted event to GreeterProjection: {:?}",
         projection.is_recording
     );
+    let message = event.message.as_ref().map(|g| &*g.message).unwrap_or("-/-");
+    projection.greeting_count += 1;
+    projection.last_greeting = message.to_string();
+    projection.last_greeting_timestamp = event_message.timestamp;
+    projection.recent_greetings.retain(|receipt| receipt.expires_at > event_message.timestamp);
+    if !event.idempotency_key.is_empty() {
+        projection.recent_greetings.push(GreetingReceipt {
+            idempotency_key: event.idempotency_key,
+            acknowledgement: acknowledgement(message),
+            timestamp: event_message.timestamp,
+            expires_at: event_message.timestamp.saturating_add(event.idempotency_window_millis),
+        });
+    }
+}
+
+#[dendrite_macros::event_sourcing_handler]
+fn handle_greeting_retracted_source_event(event: GreetingRetractedEvent, mut projection: GreeterProjection) {
+    debug!("Apply GreetingRetractedEvent to GreeterProjection: {:?}", event.sequence_number);
+    projection.retracted_greetings.push(event.sequence_number);
+}
+
+#[dendrite_macros::event_sourcing_handler]
+fn handle_quota_assigned_source_event(event: QuotaAssignedEvent, mut projection: GreeterProjection) {
+    debug!("Apply QuotaAssignedEvent to GreeterProjection: {:?}", event.quota);
+    projection.quota = event.quota;
 }
 
 #[dendrite_macros::event_sourcing_handler]
@@ -153,3 +384,246 @@ fn handle_stopped_recording_source_event(
     );
     projection.is_recording = false;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::example_aggregate::AggregateTestFixture;
+    use crate::proto_example::Greeting;
+
+    const GREETER_ID: &str = "greeter";
+
+    fn settings() -> GreeterSettings {
+        GreeterSettings::from_config(&ApplicationConfig::default()).expect("settings")
+    }
+
+    fn fixture() -> AggregateTestFixture<GreeterProjection, GreeterSettings> {
+        let aggregate_definition = greeter_aggregate(settings(), &SnapshotConfig::default()).expect("aggregate definition");
+        AggregateTestFixture::new(aggregate_definition, GREETER_ID)
+    }
+
+    fn quota(max_greetings: i64) -> QuotaAssignedEvent {
+        QuotaAssignedEvent {
+            quota: Some(GreetingQuota {
+                max_greetings,
+                min_interval_millis: 1000,
+            }),
+        }
+    }
+
+    fn greeted(message: &str, idempotency_key: &str) -> GreetedEvent {
+        GreetedEvent {
+            message: Some(greeting(message)),
+            idempotency_key: idempotency_key.to_string(),
+            idempotency_window_millis: settings().idempotency_window_millis,
+        }
+    }
+
+    fn greeting(message: &str) -> Greeting {
+        Greeting {
+            message: message.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn greet(message: &str) -> GreetCommand {
+        GreetCommand {
+            aggregate_identifier: GREETER_ID.to_string(),
+            message: Some(greeting(message)),
+            ..Default::default()
+        }
+    }
+
+    fn record(expected_version: Option<i64>) -> RecordCommand {
+        RecordCommand {
+            aggregate_identifier: GREETER_ID.to_string(),
+            expected_version,
+        }
+    }
+
+    fn stop(expected_version: Option<i64>) -> StopCommand {
+        StopCommand {
+            aggregate_identifier: GREETER_ID.to_string(),
+            expected_version,
+        }
+    }
+
+    #[tokio::test]
+    async fn first_greeting_assigns_quota() {
+        let outcome = fixture().when("GreetCommand", &greet("Hi")).await;
+        assert_eq!(outcome.event_types(), vec!["QuotaAssignedEvent", "GreetedEvent"]);
+        assert_eq!(outcome.event::<QuotaAssignedEvent>(0).unwrap().quota, Some(settings().quota));
+        assert_eq!(outcome.event::<GreetedEvent>(1).unwrap().message, Some(greeting("Hi")));
+        let acknowledgement = outcome.reply::<Acknowledgement>().unwrap().unwrap();
+        assert_eq!(acknowledgement.message, "ACK! Hi");
+        assert_eq!(acknowledgement.version, 1);
+    }
+
+    #[tokio::test]
+    async fn next_greeting_uses_assigned_quota() {
+        let outcome = fixture()
+            .given("QuotaAssignedEvent", &quota(5))
+            .given("GreetedEvent", &greeted("Hi", ""))
+            .when("GreetCommand", &greet("Hello"))
+            .await;
+        assert_eq!(outcome.event_types(), vec!["GreetedEvent"]);
+        assert_eq!(outcome.reply::<Acknowledgement>().unwrap().unwrap().version, 2);
+    }
+
+    #[tokio::test]
+    async fn greeting_error_is_invalid() {
+        let outcome = fixture().when("GreetCommand", &greet("ERROR")).await;
+        assert!(outcome.events.is_empty());
+        assert!(matches!(outcome.error(), Some(DomainError::InvalidArgument(_))));
+    }
+
+    #[tokio::test]
+    async fn greeting_while_stopped_emits_nothing() {
+        let outcome = fixture()
+            .given("StoppedRecordingEvent", &StoppedRecordingEvent {})
+            .when("GreetCommand", &greet("Hi"))
+            .await;
+        assert!(outcome.events.is_empty());
+        let acknowledgement = outcome.reply::<Acknowledgement>().unwrap().unwrap();
+        assert_eq!(acknowledgement.message, "Hello Hi!");
+        assert_eq!(acknowledgement.version, 0);
+    }
+
+    #[tokio::test]
+    async fn same_greeting_twice_is_duplicate() {
+        let outcome = fixture()
+            .given("QuotaAssignedEvent", &quota(5))
+            .given("GreetedEvent", &greeted("Hi", ""))
+            .when("GreetCommand", &greet("Hi"))
+            .await;
+        assert!(outcome.events.is_empty());
+        assert!(matches!(outcome.error(), Some(DomainError::DuplicateGreeting(_))));
+    }
+
+    #[tokio::test]
+    async fn greeting_beyond_quota_is_rejected() {
+        let outcome = fixture()
+            .given("QuotaAssignedEvent", &quota(1))
+            .given("GreetedEvent", &greeted("Hi", ""))
+            .when("GreetCommand", &greet("Hello"))
+            .await;
+        assert!(matches!(outcome.error(), Some(DomainError::QuotaExceeded(_))));
+    }
+
+    #[tokio::test]
+    async fn greeting_too_soon_is_rejected() {
+        let now = now_millis().unwrap();
+        let outcome = fixture()
+            .given("QuotaAssignedEvent", &quota(5))
+            .given_at(now, "GreetedEvent", &greeted("Hi", ""))
+            .when("GreetCommand", &greet("Hello"))
+            .await;
+        assert!(matches!(outcome.error(), Some(DomainError::GreetingTooSoon(_))));
+    }
+
+    #[tokio::test]
+    async fn repeated_idempotency_key_replays_acknowledgement() {
+        let now = now_millis().unwrap();
+        let command = GreetCommand {
+            idempotency_key: "key".to_string(),
+            ..greet("Hi")
+        };
+        let outcome = fixture()
+            .given("QuotaAssignedEvent", &quota(5))
+            .given_at(now, "GreetedEvent", &greeted("Hi", "key"))
+            .when("GreetCommand", &command)
+            .await;
+        assert!(outcome.events.is_empty());
+        let acknowledgement = outcome.reply::<Acknowledgement>().unwrap().unwrap();
+        assert_eq!(acknowledgement.message, "ACK! Hi");
+        assert_eq!(acknowledgement.version, 1);
+    }
+
+    #[tokio::test]
+    async fn idempotency_key_expires_with_recorded_window() {
+        let now = now_millis().unwrap();
+        let command = GreetCommand {
+            idempotency_key: "key".to_string(),
+            ..greet("Hi")
+        };
+        let event = GreetedEvent {
+            idempotency_window_millis: 1000,
+            ..greeted("Hi", "key")
+        };
+        let outcome = fixture()
+            .given("QuotaAssignedEvent", &quota(5))
+            .given_at(now - 2000, "GreetedEvent", &event)
+            .when("GreetCommand", &command)
+            .await;
+        assert!(matches!(outcome.error(), Some(DomainError::DuplicateGreeting(_))));
+    }
+
+    #[tokio::test]
+    async fn greeting_with_stale_version_conflicts() {
+        let command = GreetCommand {
+            expected_version: Some(0),
+            ..greet("Hello")
+        };
+        let outcome = fixture()
+            .given("QuotaAssignedEvent", &quota(5))
+            .given("GreetedEvent", &greeted("Hi", ""))
+            .when("GreetCommand", &command)
+            .await;
+        assert!(outcome.events.is_empty());
+        assert!(matches!(outcome.error(), Some(DomainError::Conflict(_))));
+    }
+
+    #[tokio::test]
+    async fn record_while_stopped_starts_recording() {
+        let outcome = fixture()
+            .given("StoppedRecordingEvent", &StoppedRecordingEvent {})
+            .when("RecordCommand", &record(Some(0)))
+            .await;
+        assert_eq!(outcome.event_types(), vec!["StartedRecordingEvent"]);
+        assert_eq!(outcome.reply::<GreeterVersion>().unwrap().unwrap().version, 1);
+    }
+
+    #[tokio::test]
+    async fn record_while_recording_emits_nothing() {
+        let outcome = fixture().when("RecordCommand", &record(None)).await;
+        assert!(outcome.events.is_empty());
+        assert_eq!(outcome.reply::<GreeterVersion>().unwrap().unwrap().version, -1);
+    }
+
+    #[tokio::test]
+    async fn record_with_stale_version_conflicts() {
+        let outcome = fixture()
+            .given("StoppedRecordingEvent", &StoppedRecordingEvent {})
+            .when("RecordCommand", &record(Some(-1)))
+            .await;
+        assert!(outcome.events.is_empty());
+        assert!(matches!(outcome.error(), Some(DomainError::Conflict(_))));
+    }
+
+    #[tokio::test]
+    async fn stop_while_recording_stops_recording() {
+        let outcome = fixture().when("StopCommand", &stop(Some(-1))).await;
+        assert_eq!(outcome.event_types(), vec!["StoppedRecordingEvent"]);
+        assert_eq!(outcome.reply::<GreeterVersion>().unwrap().unwrap().version, 0);
+    }
+
+    #[tokio::test]
+    async fn stop_while_stopped_emits_nothing() {
+        let outcome = fixture()
+            .given("StoppedRecordingEvent", &StoppedRecordingEvent {})
+            .when("StopCommand", &stop(None))
+            .await;
+        assert!(outcome.events.is_empty());
+        assert_eq!(outcome.reply::<GreeterVersion>().unwrap().unwrap().version, 0);
+    }
+
+    #[tokio::test]
+    async fn stop_with_stale_version_conflicts() {
+        let outcome = fixture()
+            .given("StartedRecordingEvent", &StartedRecordingEvent {})
+            .when("StopCommand", &stop(Some(1)))
+            .await;
+        assert!(outcome.events.is_empty());
+        assert!(matches!(outcome.error(), Some(DomainError::Conflict(_))));
+    }
+}
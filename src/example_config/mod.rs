@@ -0,0 +1,779 @@
+//! Runtime configuration of the example application.
+//!
+//! The configuration is read from a YAML file: the file named by environment variable `APPLICATION_CONFIG`,
+//! or else `etc/application.yaml` or `etc/application-local.yaml`, whichever exists. Any setting can be
+//! overridden with an environment variable `APPLICATION_<SECTION>__<KEY>`, _e.g._,
+//! `APPLICATION_SERVER__ADDRESS=0.0.0.0:9181`. Segments in upper case name sections and fields, and are lowercased.
+//! Other segments are map keys that keep their case, _e.g._, `APPLICATION_DEADLINES__METHODS__Greet=5000`.
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info};
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::env;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use tonic::codegen::http::{HeaderName, HeaderValue};
+
+const CONFIG_FILE_VARIABLE: &str = "APPLICATION_CONFIG";
+const DEFAULT_CONFIG_FILES: &[&str] = &["etc/application.yaml", "etc/application-local.yaml"];
+const OVERRIDE_PREFIX: &str = "APPLICATION_";
+const OVERRIDE_SEPARATOR: &str = "__";
+
+/// The complete configuration of the application.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ApplicationConfig {
+    pub server: ServerConfig,
+    pub rest: RestConfig,
+    pub metrics: MetricsConfig,
+    pub axon_server: AxonServerConfig,
+    pub elastic_search: ElasticSearchConfig,
+    pub auth: AuthConfig,
+    pub trusted_keys: TrustedKeysConfig,
+    pub workers: WorkerLabels,
+    pub supervision: SupervisionConfig,
+    pub health: HealthConfig,
+    pub tracing: TracingConfig,
+    pub logging: LoggingConfig,
+    pub shutdown: ShutdownConfig,
+    pub deadlines: DeadlineConfig,
+    pub idempotency: IdempotencyConfig,
+    pub snapshots: SnapshotConfig,
+    pub quota: QuotaConfig,
+    pub channels: ChannelSizes,
+}
+
+/// Settings for the gRPC listener.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub address: String,
+    /// Serve the gRPC reflection service, so that clients can discover the services without local proto files.
+    pub reflection: bool,
+    pub grpc_web: GrpcWebConfig,
+    pub tls: TlsConfig,
+}
+
+/// Settings for gRPC-Web and CORS on the gRPC listener.
+///
+/// An allowed origin `*` allows any origin. Headers `grpc-status` and `grpc-message` are always exposed.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GrpcWebConfig {
+    pub enabled: bool,
+    pub allowed_origins: Vec<String>,
+    pub exposed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age_seconds: u64,
+}
+
+/// Settings for TLS on the gRPC listener.
+///
+/// The files contain PEM data and are read again when the process receives SIGHUP. When `client_ca_file` is set,
+/// client certificates are verified against the certificates in that file, and the subject of a verified client
+/// certificate (_e.g._, `CN=client, O=Example`) is granted the roles that are listed for it in `certificate_roles`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    pub certificate_file: String,
+    pub key_file: String,
+    pub client_ca_file: String,
+    pub require_client_certificate: bool,
+    pub certificate_roles: BTreeMap<String, Vec<String>>,
+}
+
+/// Settings for the HTTP/JSON gateway to the `GreeterService`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RestConfig {
+    pub enabled: bool,
+    pub address: String,
+}
+
+/// Settings for the Prometheus metrics endpoint.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub address: String,
+}
+
+/// Settings for the connection to AxonServer.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AxonServerConfig {
+    pub host: String,
+    pub port: u32,
+    pub label: String,
+    pub platform_name: String,
+}
+
+/// Names of the indices and query models in Elastic Search.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ElasticSearchConfig {
+    pub greetings_index: String,
+    pub greetings_query_model: String,
+}
+
+/// Settings for the access tokens that are issued by the `ConfigurationService`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub token_lifetime_seconds: u64,
+    pub roles_property_prefix: String,
+}
+
+/// Sources of the public keys that are trusted from the start. See module `example_trusted_keys`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TrustedKeysConfig {
+    pub files: Vec<String>,
+    pub directories: Vec<String>,
+    pub variable: String,
+}
+
+/// The labels of the workers that are spawned on the `AxonServerHandle`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WorkerLabels {
+    pub platform: String,
+    pub command: String,
+    pub trust_command: String,
+    pub event: String,
+    pub replica: String,
+    pub auth: String,
+    pub query: String,
+    pub grpc_server: String,
+}
+
+/// When a supervised worker is restarted after its task stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    Never,
+    Always,
+    OnFailure,
+}
+
+/// Restart policies and limits for supervised workers. See module `example_supervisor`.
+///
+/// The keys of `policies` are the names of the fields of `WorkerLabels`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SupervisionConfig {
+    pub default_policy: RestartPolicy,
+    pub policies: BTreeMap<String, RestartPolicy>,
+    pub initial_backoff_millis: u64,
+    pub max_backoff_millis: u64,
+    pub jitter: f64,
+    pub max_restarts: u32,
+    pub restart_window_seconds: u64,
+}
+
+/// Settings for the health checks that are reported by the `grpc.health.v1.Health` service.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    pub probe_interval_seconds: u64,
+    pub probe_timeout_seconds: u64,
+}
+
+/// Where the spans of the distributed traces are sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TraceExporter {
+    None,
+    Stdout,
+    Otlp,
+}
+
+/// Settings for OpenTelemetry tracing. See module `example_tracing`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TracingConfig {
+    pub exporter: TraceExporter,
+    pub otlp_endpoint: String,
+    pub service_name: String,
+}
+
+/// How log lines are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Settings for logging. See module `example_logging`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+}
+
+/// Settings for the graceful shutdown. See module `example_shutdown`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    /// The time that requests in flight, and each worker in turn, get to complete.
+    pub grace_period_seconds: u64,
+}
+
+/// The maximum time that commands and queries may take, by `GreeterService` method. See module `example_deadline`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DeadlineConfig {
+    pub default_timeout_millis: u64,
+    pub methods: BTreeMap<String, u64>,
+}
+
+/// Settings for the idempotency keys of `Greet` requests.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct IdempotencyConfig {
+    /// How long a repeated request with the same key gets the original acknowledgement.
+    pub window_seconds: u64,
+}
+
+/// Settings for snapshots of aggregate projections.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SnapshotConfig {
+    /// Number of events between snapshots of an aggregate; zero disables snapshots.
+    pub interval: u64,
+}
+
+/// The quota that is assigned to a greeter when it receives its first greeting.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct QuotaConfig {
+    /// Maximum number of greetings per greeter; zero means unlimited.
+    pub max_greetings: u64,
+    /// Minimum time between two greetings of the same greeter.
+    pub min_interval_millis: u64,
+}
+
+/// Capacities of internal channels.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ChannelSizes {
+    pub server_id: usize,
+    pub greetings: usize,
+    pub search: usize,
+    pub trusted_keys: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: "0.0.0.0:8181".to_string(),
+            reflection: false,
+            grpc_web: GrpcWebConfig::default(),
+            tls: TlsConfig::default(),
+        }
+    }
+}
+
+impl Default for GrpcWebConfig {
+    fn default() -> Self {
+        GrpcWebConfig {
+            enabled: false,
+            allowed_origins: Vec::new(),
+            exposed_headers: Vec::new(),
+            allow_credentials: false,
+            max_age_seconds: 86400,
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            enabled: false,
+            certificate_file: "tls/server.crt".to_string(),
+            key_file: "tls/server.key".to_string(),
+            client_ca_file: String::new(),
+            require_client_certificate: false,
+            certificate_roles: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for RestConfig {
+    fn default() -> Self {
+        RestConfig {
+            enabled: false,
+            address: "0.0.0.0:8282".to_string(),
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enabled: false,
+            address: "0.0.0.0:9181".to_string(),
+        }
+    }
+}
+
+impl Default for AxonServerConfig {
+    fn default() -> Self {
+        AxonServerConfig {
+            host: "proxy".to_string(),
+            port: 8124,
+            label: "API".to_string(),
+            platform_name: "Rustic".to_string(),
+        }
+    }
+}
+
+impl Default for ElasticSearchConfig {
+    fn default() -> Self {
+        ElasticSearchConfig {
+            greetings_index: "greetings".to_string(),
+            greetings_query_model: "greeting".to_string(),
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            token_lifetime_seconds: 3600,
+            roles_property_prefix: "roles.".to_string(),
+        }
+    }
+}
+
+impl Default for TrustedKeysConfig {
+    fn default() -> Self {
+        TrustedKeysConfig {
+            files: Vec::new(),
+            directories: vec!["trusted-keys".to_string()],
+            variable: "TRUSTED_KEYS".to_string(),
+        }
+    }
+}
+
+impl Default for WorkerLabels {
+    fn default() -> Self {
+        WorkerLabels {
+            platform: "Platform".to_string(),
+            command: "Command".to_string(),
+            trust_command: "Trust store".to_string(),
+            event: "Elastic".to_string(),
+            replica: "Replica".to_string(),
+            auth: "Auth".to_string(),
+            query: "Query".to_string(),
+            grpc_server: "gRPC server".to_string(),
+        }
+    }
+}
+
+impl Default for SupervisionConfig {
+    fn default() -> Self {
+        // The workers of dendrite itself don't report failures, so those are restarted whenever they stop.
+        let policies = ["platform", "replica", "auth"]
+            .iter()
+            .map(|key| (key.to_string(), RestartPolicy::Always))
+            .collect();
+        SupervisionConfig {
+            default_policy: RestartPolicy::OnFailure,
+            policies,
+            initial_backoff_millis: 500,
+            max_backoff_millis: 30_000,
+            jitter: 0.2,
+            max_restarts: 10,
+            restart_window_seconds: 300,
+        }
+    }
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            probe_interval_seconds: 5,
+            probe_timeout_seconds: 2,
+        }
+    }
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        TracingConfig {
+            exporter: TraceExporter::None,
+            otlp_endpoint: "http://localhost:4317".to_string(),
+            service_name: "dendrite-example".to_string(),
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            format: LogFormat::Text,
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            grace_period_seconds: 20,
+        }
+    }
+}
+
+impl Default for DeadlineConfig {
+    fn default() -> Self {
+        DeadlineConfig {
+            default_timeout_millis: 30_000,
+            methods: BTreeMap::new(),
+        }
+    }
+}
+
+impl DeadlineConfig {
+    /// The configured timeout for the given method, _e.g._, `Greet`.
+    pub fn timeout_for(&self, method: &str) -> Duration {
+        let millis = self.methods.get(method).copied().unwrap_or(self.default_timeout_millis);
+        Duration::from_millis(millis)
+    }
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        IdempotencyConfig {
+            window_seconds: 86_400,
+        }
+    }
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        SnapshotConfig {
+            interval: 100,
+        }
+    }
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        QuotaConfig {
+            max_greetings: 10_000,
+            min_interval_millis: 1000,
+        }
+    }
+}
+
+impl Default for ChannelSizes {
+    fn default() -> Self {
+        ChannelSizes {
+            server_id: 10,
+            greetings: 4,
+            search: 4,
+            trusted_keys: 4,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// The address that the gRPC server listens on.
+    pub fn socket_address(&self) -> Result<SocketAddr> {
+        self.address
+            .parse()
+            .with_context(|| format!("Invalid server.address: {:?}", self.address))
+    }
+}
+
+impl TlsConfig {
+    /// Checks that the required files are named.
+    pub fn validate(&self) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        non_empty("server.tls.certificate_file", &self.certificate_file)?;
+        non_empty("server.tls.key_file", &self.key_file)?;
+        if self.require_client_certificate && self.client_ca_file.is_empty() {
+            return Err(anyhow!("Missing value for: server.tls.client_ca_file"));
+        }
+        Ok(())
+    }
+}
+
+impl RestConfig {
+    /// The address that the REST gateway listens on.
+    pub fn socket_address(&self) -> Result<SocketAddr> {
+        self.address
+            .parse()
+            .with_context(|| format!("Invalid rest.address: {:?}", self.address))
+    }
+}
+
+impl MetricsConfig {
+    /// The address that the metrics endpoint listens on.
+    pub fn socket_address(&self) -> Result<SocketAddr> {
+        self.address
+            .parse()
+            .with_context(|| format!("Invalid metrics.address: {:?}", self.address))
+    }
+}
+
+impl GrpcWebConfig {
+    /// Returns `true` if any origin is allowed.
+    pub fn allows_all_origins(&self) -> bool {
+        self.allowed_origins.iter().any(|origin| origin == "*")
+    }
+
+    /// Checks that the origins and headers are valid header values and names.
+    pub fn validate(&self) -> Result<()> {
+        if self.enabled && self.allowed_origins.is_empty() {
+            return Err(anyhow!("Missing value for: server.grpc_web.allowed_origins"));
+        }
+        for origin in &self.allowed_origins {
+            HeaderValue::from_str(origin)
+                .with_context(|| format!("Invalid origin in server.grpc_web.allowed_origins: {:?}", origin))?;
+        }
+        for header in &self.exposed_headers {
+            HeaderName::from_bytes(header.as_bytes())
+                .with_context(|| format!("Invalid header in server.grpc_web.exposed_headers: {:?}", header))?;
+        }
+        Ok(())
+    }
+}
+
+impl SupervisionConfig {
+    /// The restart policy for the worker with the given key.
+    pub fn policy_for(&self, key: &str) -> RestartPolicy {
+        self.policies
+            .get(key)
+            .copied()
+            .unwrap_or(self.default_policy)
+    }
+}
+
+impl IdempotencyConfig {
+    /// The idempotency window in milliseconds, as recorded in `GreetedEvent`.
+    pub fn window_millis(&self) -> Result<i64> {
+        self.window_seconds
+            .checked_mul(1000)
+            .and_then(|millis| i64::try_from(millis).ok())
+            .ok_or_else(|| anyhow!("Idempotency window too large: idempotency.window_seconds"))
+    }
+}
+
+impl ApplicationConfig {
+    /// Loads the configuration from the YAML file and the environment, and validates it.
+    pub fn load() -> Result<ApplicationConfig> {
+        let (path, required) = match env::var(CONFIG_FILE_VARIABLE) {
+            Ok(path) => (path, true),
+            Err(_) => {
+                let path = DEFAULT_CONFIG_FILES
+                    .iter()
+                    .find(|path| Path::new(path).exists())
+                    .unwrap_or(&DEFAULT_CONFIG_FILES[0]);
+                (path.to_string(), false)
+            }
+        };
+        let mut document = read_document(Path::new(&path), required)?;
+        apply_overrides(&mut document, env::vars())?;
+        let config: ApplicationConfig = serde_yaml::from_value(document)
+            .with_context(|| format!("Invalid configuration: {:?}", path))?;
+        config.validate()?;
+        debug!("Configuration: {:?}", config);
+        Ok(config)
+    }
+
+    /// Checks the consistency of the configuration.
+    pub fn validate(&self) -> Result<()> {
+        self.server.socket_address()?;
+        self.server.grpc_web.validate()?;
+        self.server.tls.validate()?;
+        self.rest.socket_address()?;
+        self.metrics.socket_address()?;
+        non_empty("axon_server.host", &self.axon_server.host)?;
+        if self.axon_server.port == 0 || self.axon_server.port > 65535 {
+            return Err(anyhow!(
+                "Invalid axon_server.port: {:?}",
+                self.axon_server.port
+            ));
+        }
+        non_empty("axon_server.label", &self.axon_server.label)?;
+        non_empty("axon_server.platform_name", &self.axon_server.platform_name)?;
+        index_name(
+            "elastic_search.greetings_index",
+            &self.elastic_search.greetings_index,
+        )?;
+        non_empty(
+            "elastic_search.greetings_query_model",
+            &self.elastic_search.greetings_query_model,
+        )?;
+        if self.auth.token_lifetime_seconds == 0 {
+            return Err(anyhow!("Token lifetime must be positive: auth.token_lifetime_seconds"));
+        }
+        let labels = &self.workers;
+        for (key, label) in &[
+            ("workers.platform", &labels.platform),
+            ("workers.command", &labels.command),
+            ("workers.trust_command", &labels.trust_command),
+            ("workers.event", &labels.event),
+            ("workers.replica", &labels.replica),
+            ("workers.auth", &labels.auth),
+            ("workers.query", &labels.query),
+            ("workers.grpc_server", &labels.grpc_server),
+        ] {
+            non_empty(key, label)?;
+        }
+        let supervision = &self.supervision;
+        if supervision.initial_backoff_millis == 0
+            || supervision.max_backoff_millis < supervision.initial_backoff_millis
+        {
+            return Err(anyhow!(
+                "Invalid supervision backoff: {:?}..{:?}",
+                supervision.initial_backoff_millis,
+                supervision.max_backoff_millis
+            ));
+        }
+        if !(0.0..=1.0).contains(&supervision.jitter) {
+            return Err(anyhow!("Invalid supervision.jitter: {:?}", supervision.jitter));
+        }
+        if supervision.restart_window_seconds == 0 {
+            return Err(anyhow!("Restart window must be positive: supervision.restart_window_seconds"));
+        }
+        if self.health.probe_interval_seconds == 0 || self.health.probe_timeout_seconds == 0 {
+            return Err(anyhow!("Health probe interval and timeout must be positive"));
+        }
+        if self.tracing.exporter == TraceExporter::Otlp {
+            non_empty("tracing.otlp_endpoint", &self.tracing.otlp_endpoint)?;
+        }
+        non_empty("tracing.service_name", &self.tracing.service_name)?;
+        if self.shutdown.grace_period_seconds == 0 {
+            return Err(anyhow!("Grace period must be positive: shutdown.grace_period_seconds"));
+        }
+        if self.deadlines.default_timeout_millis == 0 || self.deadlines.methods.values().any(|millis| *millis == 0) {
+            return Err(anyhow!("Timeouts must be positive: deadlines"));
+        }
+        if self.idempotency.window_seconds == 0 {
+            return Err(anyhow!("Idempotency window must be positive: idempotency.window_seconds"));
+        }
+        self.idempotency.window_millis()?;
+        if i64::try_from(self.quota.max_greetings).is_err() || i64::try_from(self.quota.min_interval_millis).is_err() {
+            return Err(anyhow!("Quota too large: quota"));
+        }
+        let channels = &self.channels;
+        for (key, size) in &[
+            ("channels.server_id", channels.server_id),
+            ("channels.greetings", channels.greetings),
+            ("channels.search", channels.search),
+            ("channels.trusted_keys", channels.trusted_keys),
+        ] {
+            if *size == 0 {
+                return Err(anyhow!("Channel size must be positive: {}", key));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_document(path: &Path, required: bool) -> Result<Value> {
+    if !path.exists() {
+        if required {
+            return Err(anyhow!("Missing configuration file: {:?}", path));
+        }
+        info!("No configuration file: {:?}: using defaults", path);
+        return Ok(Value::Mapping(Mapping::new()));
+    }
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Can't read configuration file: {:?}", path))?;
+    let document: Value = serde_yaml::from_str(&text)
+        .with_context(|| format!("Can't parse configuration file: {:?}", path))?;
+    match document {
+        Value::Null => Ok(Value::Mapping(Mapping::new())),
+        Value::Mapping(_) => Ok(document),
+        _ => Err(anyhow!("Configuration file is not a mapping: {:?}", path)),
+    }
+}
+
+fn apply_overrides<I: Iterator<Item = (String, String)>>(document: &mut Value, variables: I) -> Result<()> {
+    for (name, value) in variables {
+        let path = match name.strip_prefix(OVERRIDE_PREFIX) {
+            Some(path) if path.contains(OVERRIDE_SEPARATOR) => path,
+            _ => continue,
+        };
+        let value: Value = serde_yaml::from_str(&value)
+            .with_context(|| format!("Invalid value for environment variable: {:?}", name))?;
+        debug!("Configuration override: {:?}", path);
+        let mut node = &mut *document;
+        for key in path.split(OVERRIDE_SEPARATOR).map(override_key) {
+            let mapping = match node {
+                Value::Mapping(mapping) => mapping,
+                _ => return Err(anyhow!("Can't override non-mapping value: {:?}", name)),
+            };
+            let key = Value::String(key);
+            if !mapping.contains_key(&key) {
+                mapping.insert(key.clone(), Value::Mapping(Mapping::new()));
+            }
+            node = mapping
+                .get_mut(&key)
+                .ok_or_else(|| anyhow!("Can't override: {:?}", name))?;
+        }
+        *node = value;
+    }
+    Ok(())
+}
+
+/// A segment of an override path in upper case is a section or a field, anything else is a map key.
+fn override_key(segment: &str) -> String {
+    if segment.chars().any(|c| c.is_ascii_lowercase()) {
+        segment.to_string()
+    } else {
+        segment.to_lowercase()
+    }
+}
+
+fn non_empty(key: &str, value: &str) -> Result<()> {
+    if value.trim().is_empty() {
+        return Err(anyhow!("Missing value for: {}", key));
+    }
+    Ok(())
+}
+
+fn index_name(key: &str, value: &str) -> Result<()> {
+    non_empty(key, value)?;
+    let valid = value
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if !valid {
+        return Err(anyhow!("Invalid Elastic Search index name for {}: {:?}", key, value));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overridden(variables: &[(&str, &str)]) -> ApplicationConfig {
+        let mut document = Value::Mapping(Mapping::new());
+        let variables = variables.iter().map(|(name, value)| (name.to_string(), value.to_string()));
+        apply_overrides(&mut document, variables).expect("overrides");
+        serde_yaml::from_value(document).expect("configuration")
+    }
+
+    #[test]
+    fn override_keeps_case_of_map_keys() {
+        let config = overridden(&[
+            ("APPLICATION_DEADLINES__DEFAULT_TIMEOUT_MILLIS", "1000"),
+            ("APPLICATION_DEADLINES__METHODS__Greet", "5000"),
+        ]);
+        assert_eq!(config.deadlines.timeout_for("Greet"), Duration::from_millis(5000));
+        assert_eq!(config.deadlines.timeout_for("Record"), Duration::from_millis(1000));
+    }
+}
@@ -0,0 +1,245 @@
+//! Correlation of requests with the commands, queries and events that they cause.
+//!
+//! Each gRPC request gets a correlation id: the value of its `x-request-id` header, or a generated UUID. The
+//! correlation id, the subject of the caller and the W3C trace context of the request travel as text metadata with
+//! the commands and queries that are sent while handling the request.
+//!
+//! AxonServer events only get the metadata that dendrite copies from the command, _i.e._, the command name, the
+//! command id and the correlation id. Therefore all fields are also encoded in the dendrite correlation id of each
+//! command, as `correlation-id=<value>;user=<value>;traceparent=<value>;tracestate=<value>`, with `%` and `;`
+//! percent-encoded in the values. The command id becomes the causation id of the event.
+
+use crate::example_auth::Claims;
+use crate::example_deadline::remaining;
+use crate::example_tracing::{remote_context, trace_context, trace_fields};
+use anyhow::{anyhow, Result};
+use dendrite::axon_server::command::Command;
+use dendrite::axon_server::common::meta_data_value::Data;
+use dendrite::axon_server::common::{MetaDataValue, ProcessingInstruction, ProcessingKey};
+use dendrite::axon_server::event::Event;
+use dendrite::axon_server::query::query_service_client::QueryServiceClient;
+use dendrite::axon_server::query::{QueryRequest, QueryResponse};
+use dendrite::axon_server::SerializedObject;
+use dendrite::axon_utils::{AxonServerHandle, SubmitCommand, VecU8Message};
+use log::debug;
+use opentelemetry::Context;
+use std::collections::HashMap;
+use tokio::time::Instant;
+use tonic::Request;
+use uuid::Uuid;
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+const CORRELATION_ID_FIELD: &str = "correlation-id";
+const USER_FIELD: &str = "user";
+const DENDRITE_CORRELATION_ID_KEY: &str = "dendrite::correlation_id";
+const DENDRITE_COMMAND_ID_KEY: &str = "dendrite::command_id";
+const FIELD_SEPARATOR: char = ';';
+
+/// The correlation id of a request, and what is known about its cause.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Correlation {
+    /// The id of the gRPC request that started it all.
+    pub correlation_id: String,
+    /// The message id of the command that caused an event.
+    pub causation_id: Option<String>,
+    /// The subject of the caller.
+    pub user: Option<String>,
+}
+
+impl Correlation {
+    /// The correlation of a gRPC request, with the subject of the verified claims as the user.
+    pub fn from_request<T>(request: &Request<T>) -> Correlation {
+        let correlation_id = request
+            .metadata()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| is_valid_request_id(value))
+            .map(str::to_string)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        let user = request
+            .extensions()
+            .get::<Claims>()
+            .and_then(Claims::subject)
+            .map(str::to_string);
+        Correlation {
+            correlation_id,
+            causation_id: None,
+            user,
+        }
+    }
+
+    /// The correlation that is stored in the given context, if any.
+    pub fn from_context(context: &Context) -> Option<Correlation> {
+        context.get::<Correlation>().cloned()
+    }
+
+    /// The correlation that is stored in the current context, if any.
+    pub fn current() -> Option<Correlation> {
+        Correlation::from_context(&Context::current())
+    }
+
+    fn from_fields(fields: &HashMap<String, String>, causation_id: Option<String>) -> Option<Correlation> {
+        let correlation_id = fields.get(CORRELATION_ID_FIELD)?.clone();
+        Some(Correlation {
+            correlation_id,
+            causation_id,
+            user: fields.get(USER_FIELD).cloned(),
+        })
+    }
+}
+
+/// The context of a gRPC request: the remote trace context and the correlation of the request.
+pub fn request_context<T>(request: &Request<T>) -> Context {
+    remote_context(request.metadata()).with_value(Correlation::from_request(request))
+}
+
+/// Creates a `SubmitCommand` that carries the current correlation and trace context, both in its metadata and in its
+/// dendrite correlation id.
+pub fn submit_command(command_type: &str, command: Box<dyn VecU8Message + Sync + Send>) -> SubmitCommand {
+    let fields = current_fields();
+    let mut submit_command = SubmitCommand::new(command_type, command);
+    for (key, value) in &fields {
+        submit_command.text_annotation(key, value);
+    }
+    if !fields.is_empty() {
+        submit_command.correlation_id(&encode_fields(&fields));
+    }
+    submit_command
+}
+
+/// Sends a query to AxonServer, like `QuerySink::send_query`, with the current correlation and trace context in its
+/// metadata, and the time that is left until the deadline as its timeout.
+///
+/// A response that carries an error fails the query with the error message of the query handler, so that the caller
+/// can restore the `DomainError`.
+pub async fn send_query(
+    axon_server_handle: &AxonServerHandle,
+    query_type: &str,
+    query: &(dyn VecU8Message + Sync),
+    deadline: Instant,
+) -> Result<Vec<SerializedObject>> {
+    let mut data = Vec::new();
+    query.encode_u8(&mut data)?;
+    let meta_data = current_fields()
+        .into_iter()
+        .map(|(key, value)| (key, text_value(value)))
+        .collect();
+    let timeout = remaining(deadline);
+    let query_request = QueryRequest {
+        message_identifier: Uuid::new_v4().to_string(),
+        query: query_type.to_string(),
+        response_type: None,
+        payload: Some(SerializedObject {
+            r#type: query_type.to_string(),
+            revision: "1".to_string(),
+            data,
+        }),
+        client_id: axon_server_handle.client_id.clone(),
+        component_name: axon_server_handle.display_name.clone(),
+        meta_data,
+        processing_instructions: vec![ProcessingInstruction {
+            key: ProcessingKey::Timeout as i32,
+            value: Some(MetaDataValue {
+                data: Some(Data::NumberValue(timeout.as_millis() as i64)),
+            }),
+        }],
+        timestamp: 0,
+    };
+    debug!("Sending query: {:?}: {:?}", query_type, axon_server_handle.display_name);
+    let mut client = QueryServiceClient::new(axon_server_handle.conn.clone());
+    let mut request = Request::new(query_request);
+    request.set_timeout(timeout);
+    let mut responses = client.query(request).await?.into_inner();
+    let mut result = Vec::new();
+    while let Some(QueryResponse { payload, error_code, error_message, .. }) = responses.message().await? {
+        if let Some(error_message) = error_message {
+            return Err(anyhow!(error_message.message));
+        }
+        if !error_code.is_empty() {
+            return Err(anyhow!("Query failed: {:?}: {}", query_type, error_code));
+        }
+        result.extend(payload);
+    }
+    Ok(result)
+}
+
+/// The correlation and trace context that were sent along with a command.
+pub fn command_context(command: &Command) -> Context {
+    with_fields(&text_fields(&command.meta_data), None)
+}
+
+/// The correlation and trace context that were sent along with a query.
+pub fn query_context(query_request: &QueryRequest) -> Context {
+    with_fields(&text_fields(&query_request.meta_data), None)
+}
+
+/// The correlation and trace context of the command that caused an event.
+///
+/// The id of that command becomes the causation id.
+pub fn event_context(event: &Event) -> Context {
+    let meta_data = text_fields(&event.meta_data);
+    let fields = meta_data
+        .get(DENDRITE_CORRELATION_ID_KEY)
+        .map(|correlation_id| decode_fields(correlation_id))
+        .unwrap_or_default();
+    with_fields(&fields, meta_data.get(DENDRITE_COMMAND_ID_KEY).cloned())
+}
+
+fn with_fields(fields: &HashMap<String, String>, causation_id: Option<String>) -> Context {
+    let context = trace_context(fields);
+    match Correlation::from_fields(fields, causation_id) {
+        Some(correlation) => context.with_value(correlation),
+        None => context,
+    }
+}
+
+fn current_fields() -> HashMap<String, String> {
+    let context = Context::current();
+    let mut fields = trace_fields(&context);
+    if let Some(correlation) = Correlation::from_context(&context) {
+        fields.insert(CORRELATION_ID_FIELD.to_string(), correlation.correlation_id);
+        if let Some(user) = correlation.user {
+            fields.insert(USER_FIELD.to_string(), user);
+        }
+    }
+    fields
+}
+
+fn is_valid_request_id(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_REQUEST_ID_LENGTH
+        && value.chars().all(|c| c.is_ascii_graphic())
+}
+
+fn text_fields(meta_data: &HashMap<String, MetaDataValue>) -> HashMap<String, String> {
+    meta_data
+        .iter()
+        .filter_map(|(key, value)| match &value.data {
+            Some(Data::TextValue(text)) => Some((key.clone(), text.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+fn text_value(text: String) -> MetaDataValue {
+    MetaDataValue {
+        data: Some(Data::TextValue(text)),
+    }
+}
+
+fn encode_fields(fields: &HashMap<String, String>) -> String {
+    let mut pairs: Vec<String> = fields
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value.replace('%', "%25").replace(FIELD_SEPARATOR, "%3B")))
+        .collect();
+    pairs.sort();
+    pairs.join(&FIELD_SEPARATOR.to_string())
+}
+
+fn decode_fields(text: &str) -> HashMap<String, String> {
+    text.split(FIELD_SEPARATOR)
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.replace("%3B", ";").replace("%25", "%")))
+        .collect()
+}
@@ -0,0 +1,80 @@
+//! Deadlines of `GreeterService` RPCs.
+//!
+//! The deadline of an RPC is the `grpc-timeout` of the request, capped by the timeout that is configured for the
+//! method in `deadlines`. Commands and queries that are sent while handling the RPC must complete before the
+//! deadline, or else the RPC fails with `DEADLINE_EXCEEDED`. The pending call to AxonServer is then abandoned, but
+//! that does not undo anything: AxonServer may still route a command that was already sent, and the aggregate may still
+//! apply it. So a `DEADLINE_EXCEEDED` on a command means that the outcome is unknown, not that nothing happened. A
+//! query also carries the remaining time to AxonServer, both as its gRPC timeout and as processing instruction
+//! `TIMEOUT`.
+
+use crate::example_config::DeadlineConfig;
+use crate::example_error::DomainError;
+use anyhow::Result;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::{timeout_at, Instant};
+use tonic::{Request, Status};
+
+const TIMEOUT_HEADER: &str = "grpc-timeout";
+const MAX_TIMEOUT_DIGITS: usize = 8;
+
+/// The deadline of a request for the given method.
+///
+/// Fails with `INVALID_ARGUMENT` if the `grpc-timeout` header is malformed.
+pub fn request_deadline<T>(request: &Request<T>, method: &str, config: &DeadlineConfig) -> Result<Instant, Status> {
+    let configured = config.timeout_for(method);
+    let timeout = match request.metadata().get(TIMEOUT_HEADER) {
+        Some(value) => {
+            let requested = value
+                .to_str()
+                .ok()
+                .and_then(parse_timeout)
+                .ok_or_else(|| Status::invalid_argument("Malformed grpc-timeout header"))?;
+            requested.min(configured)
+        }
+        None => configured,
+    };
+    Ok(Instant::now() + timeout)
+}
+
+/// Waits for the given command or query dispatch, but not beyond the deadline.
+///
+/// When the deadline passes, the dispatch is dropped. That only abandons the client side of the call to AxonServer: a
+/// command may still be applied.
+pub async fn within_deadline<T, F>(deadline: Instant, operation: &str, dispatch: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    match timeout_at(deadline, dispatch).await {
+        Ok(result) => result,
+        Err(_) => Err(DomainError::DeadlineExceeded(format!("Deadline exceeded: {}", operation)).into()),
+    }
+}
+
+/// The time that is left until the deadline.
+pub fn remaining(deadline: Instant) -> Duration {
+    deadline.saturating_duration_since(Instant::now())
+}
+
+/// Parses the value of a `grpc-timeout` header, _e.g._, `250m` or `5S`.
+fn parse_timeout(value: &str) -> Option<Duration> {
+    if value.len() < 2 {
+        return None;
+    }
+    let (digits, unit) = value.split_at(value.len() - 1);
+    if digits.len() > MAX_TIMEOUT_DIGITS || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = digits.parse().ok()?;
+    let timeout = match unit {
+        "H" => Duration::from_secs(amount * 3600),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    };
+    Some(timeout)
+}
@@ -0,0 +1,235 @@
+use bytes::Bytes;
+use prost::Message;
+use prost_types::Any;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use tonic::{Code, Status};
+use tonic_types::pb::{ErrorInfo, Status as RpcStatus};
+
+/// Domain of the `google.rpc.ErrorInfo` details that are attached to a `Status`.
+const ERROR_DOMAIN: &str = "dendrite_example";
+
+/// Type URL of the `google.rpc.ErrorInfo` details that are attached to a `Status`.
+pub const ERROR_INFO_TYPE_URL: &str = "type.googleapis.com/google.rpc.ErrorInfo";
+
+/// Errors that can be raised by command handlers, query handlers and the API layer.
+///
+/// Command handlers return these wrapped in `anyhow::Error`. AxonServer only forwards the text of the
+/// error to the caller, so the `Display` implementation starts with the reason, which allows
+/// function `DomainError::parse` to restore the error on the other side.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DomainError {
+    /// The command or query is malformed or contains unacceptable values.
+    InvalidArgument(String),
+    /// The command is not applicable to the current state of the aggregate.
+    FailedPrecondition(String),
+    /// The aggregate is not at the version that the command expects.
+    Conflict(String),
+    /// The greeting repeats the previous greeting of the greeter.
+    DuplicateGreeting(String),
+    /// The greeter has used up its quota of greetings.
+    QuotaExceeded(String),
+    /// The greeting follows the previous greeting of the greeter too closely.
+    GreetingTooSoon(String),
+    /// The caller did not present valid credentials.
+    Unauthenticated(String),
+    /// The caller is not allowed to perform the operation.
+    PermissionDenied(String),
+    /// AxonServer or another back-end service cannot be reached.
+    Unavailable(String),
+    /// The operation did not complete in time.
+    DeadlineExceeded(String),
+    /// Anything else, _e.g._, a message that could not be decoded.
+    Internal(String),
+}
+
+impl DomainError {
+    /// A stable, machine-readable identifier of the kind of error.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            DomainError::InvalidArgument(_) => "INVALID_ARGUMENT",
+            DomainError::FailedPrecondition(_) => "FAILED_PRECONDITION",
+            DomainError::Conflict(_) => "CONFLICT",
+            DomainError::DuplicateGreeting(_) => "DUPLICATE_GREETING",
+            DomainError::QuotaExceeded(_) => "QUOTA_EXCEEDED",
+            DomainError::GreetingTooSoon(_) => "GREETING_TOO_SOON",
+            DomainError::Unauthenticated(_) => "UNAUTHENTICATED",
+            DomainError::PermissionDenied(_) => "PERMISSION_DENIED",
+            DomainError::Unavailable(_) => "UNAVAILABLE",
+            DomainError::DeadlineExceeded(_) => "DEADLINE_EXCEEDED",
+            DomainError::Internal(_) => "INTERNAL",
+        }
+    }
+
+    /// The human-readable description of the error.
+    pub fn message(&self) -> &str {
+        match self {
+            DomainError::InvalidArgument(message)
+            | DomainError::FailedPrecondition(message)
+            | DomainError::Conflict(message)
+            | DomainError::DuplicateGreeting(message)
+            | DomainError::QuotaExceeded(message)
+            | DomainError::GreetingTooSoon(message)
+            | DomainError::Unauthenticated(message)
+            | DomainError::PermissionDenied(message)
+            | DomainError::Unavailable(message)
+            | DomainError::DeadlineExceeded(message)
+            | DomainError::Internal(message) => message,
+        }
+    }
+
+    /// The gRPC status code that corresponds to this error.
+    pub fn code(&self) -> Code {
+        match self {
+            DomainError::InvalidArgument(_) => Code::InvalidArgument,
+            DomainError::FailedPrecondition(_) | DomainError::DuplicateGreeting(_) => Code::FailedPrecondition,
+            DomainError::Conflict(_) => Code::Aborted,
+            DomainError::QuotaExceeded(_) | DomainError::GreetingTooSoon(_) => Code::ResourceExhausted,
+            DomainError::Unauthenticated(_) => Code::Unauthenticated,
+            DomainError::PermissionDenied(_) => Code::PermissionDenied,
+            DomainError::Unavailable(_) => Code::Unavailable,
+            DomainError::DeadlineExceeded(_) => Code::DeadlineExceeded,
+            DomainError::Internal(_) => Code::Internal,
+        }
+    }
+
+    /// Restores a `DomainError` from its textual representation.
+    pub fn parse(text: &str) -> Option<DomainError> {
+        let (reason, message) = text.split_once(": ")?;
+        DomainError::from_reason(reason, message)
+    }
+
+    /// Restores a `DomainError` from its reason and message.
+    fn from_reason(reason: &str, message: &str) -> Option<DomainError> {
+        let message = message.to_string();
+        let error = match reason {
+            "INVALID_ARGUMENT" => DomainError::InvalidArgument(message),
+            "FAILED_PRECONDITION" => DomainError::FailedPrecondition(message),
+            "CONFLICT" => DomainError::Conflict(message),
+            "DUPLICATE_GREETING" => DomainError::DuplicateGreeting(message),
+            "QUOTA_EXCEEDED" => DomainError::QuotaExceeded(message),
+            "GREETING_TOO_SOON" => DomainError::GreetingTooSoon(message),
+            "UNAUTHENTICATED" => DomainError::Unauthenticated(message),
+            "PERMISSION_DENIED" => DomainError::PermissionDenied(message),
+            "UNAVAILABLE" => DomainError::Unavailable(message),
+            "DEADLINE_EXCEEDED" => DomainError::DeadlineExceeded(message),
+            "INTERNAL" => DomainError::Internal(message),
+            _ => return None,
+        };
+        Some(error)
+    }
+
+    /// Converts this error to a `Status` that carries a `google.rpc.ErrorInfo` in its details.
+    pub fn to_status(&self) -> Status {
+        let error_info = ErrorInfo {
+            reason: self.reason().to_string(),
+            domain: ERROR_DOMAIN.to_string(),
+            metadata: HashMap::new(),
+        };
+        let rpc_status = RpcStatus {
+            code: self.code() as i32,
+            message: self.message().to_string(),
+            details: vec![Any {
+                type_url: ERROR_INFO_TYPE_URL.to_string(),
+                value: error_info.encode_to_vec(),
+            }],
+        };
+        Status::with_details(
+            self.code(),
+            self.message(),
+            Bytes::from(rpc_status.encode_to_vec()),
+        )
+    }
+}
+
+/// The `google.rpc.ErrorInfo` details of a `Status`, _e.g._, as attached by `DomainError::to_status`.
+pub fn error_infos(status: &Status) -> Vec<ErrorInfo> {
+    RpcStatus::decode(Bytes::copy_from_slice(status.details()))
+        .map(|rpc_status| rpc_status.details)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|any| any.type_url == ERROR_INFO_TYPE_URL)
+        .filter_map(|any| ErrorInfo::decode(Bytes::from(any.value)).ok())
+        .collect()
+}
+
+impl Display for DomainError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.reason(), self.message())
+    }
+}
+
+impl std::error::Error for DomainError {}
+
+impl From<&anyhow::Error> for DomainError {
+    fn from(e: &anyhow::Error) -> Self {
+        if let Some(domain_error) = e.downcast_ref::<DomainError>() {
+            return domain_error.clone();
+        }
+        if let Some(status) = e.downcast_ref::<Status>() {
+            return DomainError::from(status);
+        }
+        if let Some(transport_error) = e.downcast_ref::<tonic::transport::Error>() {
+            return DomainError::Unavailable(transport_error.to_string());
+        }
+        if let Some(decode_error) = e.downcast_ref::<prost::DecodeError>() {
+            return DomainError::Internal(decode_error.to_string());
+        }
+        let text = e.to_string();
+        DomainError::parse(&text).unwrap_or(DomainError::Internal(text))
+    }
+}
+
+/// Restores the `DomainError` from the reason in the `ErrorInfo` details of the status, or else from its code.
+impl From<&Status> for DomainError {
+    fn from(status: &Status) -> Self {
+        if let Some(domain_error) = error_infos(status)
+            .iter()
+            .filter(|error_info| error_info.domain == ERROR_DOMAIN)
+            .find_map(|error_info| DomainError::from_reason(&error_info.reason, status.message()))
+        {
+            return domain_error;
+        }
+        let message = status.message().to_string();
+        match status.code() {
+            Code::InvalidArgument => DomainError::InvalidArgument(message),
+            Code::FailedPrecondition => DomainError::FailedPrecondition(message),
+            Code::Aborted => DomainError::Conflict(message),
+            Code::ResourceExhausted => DomainError::QuotaExceeded(message),
+            Code::Unauthenticated => DomainError::Unauthenticated(message),
+            Code::PermissionDenied => DomainError::PermissionDenied(message),
+            Code::Unavailable => DomainError::Unavailable(message),
+            Code::DeadlineExceeded => DomainError::DeadlineExceeded(message),
+            _ => DomainError::Internal(message),
+        }
+    }
+}
+
+impl From<prost::DecodeError> for DomainError {
+    fn from(e: prost::DecodeError) -> Self {
+        DomainError::Internal(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_restores_domain_error() {
+        for domain_error in [
+            DomainError::QuotaExceeded("quota".to_string()),
+            DomainError::GreetingTooSoon("too soon".to_string()),
+            DomainError::PermissionDenied("denied".to_string()),
+            DomainError::Unauthenticated("unknown".to_string()),
+        ] {
+            assert_eq!(DomainError::from(&domain_error.to_status()), domain_error);
+        }
+    }
+
+    #[test]
+    fn status_without_details_falls_back_to_code() {
+        let status = Status::resource_exhausted("quota");
+        assert_eq!(DomainError::from(&status), DomainError::QuotaExceeded("quota".to_string()));
+    }
+}
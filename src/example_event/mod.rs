@@ -1,56 +1,89 @@
-use crate::proto_example::{GreetedEvent, Greeting};
+use crate::example_config::ApplicationConfig;
+use crate::example_correlation::{event_context, Correlation};
+use crate::example_health::{report, Component};
+use crate::example_logging::with_message;
+use crate::example_metrics::{event_handled, observe_elastic_search, processed_token};
+use crate::example_supervisor::supervise;
+use crate::example_tracing::child_context;
+use crate::proto_example::{GreetedEvent, Greeting, GreetingRetractedEvent};
 use anyhow::{Context, Result};
 use dendrite::axon_server::event::Event;
-use dendrite::axon_utils::{empty_handler_registry, event_processor, AsyncApplicableTo, AxonServerHandle, TheHandlerRegistry, TokenStore, WorkerControl};
+use dendrite::axon_utils::{empty_handler_registry, event_processor, AsyncApplicableTo, AxonServerHandle, TheHandlerRegistry, TokenStore, WorkerControl, WorkerThread};
 use dendrite::elasticsearch::{
     create_elastic_query_model, wait_for_elastic_search, ElasticQueryModel,
 };
 use dendrite::macros as dendrite_macros;
 use dendrite::register;
-use elasticsearch::{Elasticsearch, IndexParts};
+use elasticsearch::indices::IndicesFlushParts;
+use elasticsearch::{DeleteParts, Elasticsearch, IndexParts};
 use log::{debug, error};
+use opentelemetry::trace::{FutureExt, SpanKind};
 use prost::Message;
 use serde_json::json;
-use sha2::{Digest, Sha256};
+use std::sync::Arc;
 
-pub mod trusted_generated;
+const TRACKING_TOKEN_INDEX: &str = "tracking-token";
 
 #[derive(Clone)]
-struct ExampleQueryModel(ElasticQueryModel);
+struct ExampleQueryModel {
+    elastic_query_model: ElasticQueryModel,
+    greetings_index: String,
+}
 
 #[tonic::async_trait]
 impl TokenStore for ExampleQueryModel {
     async fn store_token(&self, token: i64) {
-        self.0.store_token(token).await;
+        self.elastic_query_model.store_token(token).await;
+        processed_token(token);
     }
 
     async fn retrieve_token(&self) -> Result<i64> {
-        self.0.retrieve_token().await
+        self.elastic_query_model.retrieve_token().await
     }
 }
 
 impl ExampleQueryModel {
     pub fn get_client(&self) -> &Elasticsearch {
-        &self.0.get_client()
+        self.elastic_query_model.get_client()
+    }
+
+    /// Makes sure that the stored tracking token and the greetings survive a restart of Elastic Search.
+    async fn flush(&self) {
+        let indices = [TRACKING_TOKEN_INDEX, self.greetings_index.as_str()];
+        let response = self
+            .get_client()
+            .indices()
+            .flush(IndicesFlushParts::Index(&indices))
+            .send()
+            .await;
+        match response {
+            Ok(response) => debug!("Flushed tracking token: {:?}", response.status_code()),
+            Err(e) => error!("Error while flushing tracking token: {:?}", e),
+        }
     }
 }
 
+/// Creates a supervised worker that handles events with the given configuration.
+pub fn process_events_with(config: Arc<ApplicationConfig>) -> WorkerThread {
+    let worker_config = config.clone();
+    supervise("event", &config, move |handle, worker_control| {
+        Box::pin(internal_process_events(handle, worker_config.clone(), worker_control))
+    })
+}
+
 /// Handles events.
 ///
 /// Constructs an event handler registry and delegates to function `event_processor`.
-pub async fn process_events(axon_server_handle: AxonServerHandle, worker_control: WorkerControl) {
-    if let Err(e) = internal_process_events(axon_server_handle, worker_control).await {
-        error!("Error while handling commands: {:?}", e);
-    }
-    debug!("Stopped handling events");
-}
-
-async fn internal_process_events(axon_server_handle: AxonServerHandle, worker_control: WorkerControl) -> Result<()> {
+async fn internal_process_events(axon_server_handle: AxonServerHandle, config: Arc<ApplicationConfig>, worker_control: WorkerControl) -> Result<()> {
     let client = wait_for_elastic_search().await?;
     debug!("Elastic Search client: {:?}", client);
 
-    let elastic_query_model = create_elastic_query_model(client, "greeting".to_string());
-    let query_model = ExampleQueryModel(elastic_query_model);
+    let elastic_search_config = &config.elastic_search;
+    let elastic_query_model = create_elastic_query_model(client, elastic_search_config.greetings_query_model.clone());
+    let query_model = ExampleQueryModel {
+        elastic_query_model,
+        greetings_index: elastic_search_config.greetings_index.clone(),
+    };
 
     let mut event_handler_registry: TheHandlerRegistry<
         ExampleQueryModel,
@@ -59,10 +92,15 @@ async fn internal_process_events(axon_server_handle: AxonServerHandle, worker_co
     > = empty_handler_registry();
 
     register!(event_handler_registry, handle_greeted_event)?;
+    register!(event_handler_registry, handle_greeting_retracted_event)?;
 
-    event_processor(axon_server_handle, query_model, event_handler_registry, worker_control)
+    report(Component::EventProcessor, true).await;
+    let result = event_processor(axon_server_handle, query_model.clone(), event_handler_registry, worker_control)
         .await
-        .context("Error while handling commands")
+        .context("Error while processing events");
+    query_model.flush().await;
+    report(Component::EventProcessor, false).await;
+    result
 }
 
 #[dendrite_macros::event_handler]
@@ -71,25 +109,77 @@ pub async fn handle_greeted_event(
     query_model: ExampleQueryModel,
     message: Event,
 ) -> Result<()> {
-    debug!(
-        "Apply greeted event to ExampleQueryModel: {:?}",
-        message.timestamp
+    let context = event_context(&message);
+    let correlation = Correlation::from_context(&context);
+    let handler_context = with_message(
+        &child_context("handle_greeted_event", SpanKind::Consumer, &context),
+        "GreetedEvent",
+        Some(&message.aggregate_identifier),
     );
-    let es_client = query_model.get_client();
-    if let Some(Greeting { message }) = &event.message {
-        let mut hasher = Sha256::new();
-        Digest::update(&mut hasher, message);
-        let hash: Vec<u8> = hasher.finalize().to_vec();
-        let hash = base64::encode(hash);
-        let response = es_client
-            .index(IndexParts::IndexId("greetings", hash.as_str()))
-            .body(json!({
-                "id": hash,
-                "value": message.to_string(),
-            }))
-            .send()
-            .await;
+    async move {
+        debug!(
+            "Apply greeted event to ExampleQueryModel: {:?}",
+            message.timestamp
+        );
+        event_handled("GreetedEvent");
+        let es_client = query_model.get_client();
+        let id = greeting_id(&message.aggregate_identifier, message.aggregate_sequence_number);
+        if let Some(Greeting { message, .. }) = &event.message {
+            let request = es_client
+                .index(IndexParts::IndexId(&query_model.greetings_index, id.as_str()))
+                .body(json!({
+                    "id": id,
+                    "value": message.to_string(),
+                    "correlation_id": correlation.as_ref().map(|c| c.correlation_id.clone()),
+                    "causation_id": correlation.as_ref().and_then(|c| c.causation_id.clone()),
+                    "user": correlation.as_ref().and_then(|c| c.user.clone()),
+                }))
+                .send();
+            let elastic_search_span = child_context("Elastic Search index", SpanKind::Client, &opentelemetry::Context::current());
+            let response = observe_elastic_search("index", request.with_context(elastic_search_span)).await;
+            debug!("Elastic Search response: {:?}", response);
+        }
+        Ok(())
+    }
+    .with_context(handler_context)
+    .await
+}
+
+#[dendrite_macros::event_handler]
+pub async fn handle_greeting_retracted_event(
+    event: GreetingRetractedEvent,
+    query_model: ExampleQueryModel,
+    message: Event,
+) -> Result<()> {
+    let handler_context = with_message(
+        &child_context("handle_greeting_retracted_event", SpanKind::Consumer, &event_context(&message)),
+        "GreetingRetractedEvent",
+        Some(&message.aggregate_identifier),
+    );
+    async move {
+        debug!(
+            "Apply greeting retracted event to ExampleQueryModel: {:?}",
+            event.sequence_number
+        );
+        event_handled("GreetingRetractedEvent");
+        let es_client = query_model.get_client();
+        let id = greeting_id(&message.aggregate_identifier, event.sequence_number);
+        let request = es_client
+            .delete(DeleteParts::IndexId(&query_model.greetings_index, id.as_str()))
+            .send();
+        let elastic_search_span = child_context("Elastic Search delete", SpanKind::Client, &opentelemetry::Context::current());
+        let response = observe_elastic_search("delete", request.with_context(elastic_search_span)).await;
         debug!("Elastic Search response: {:?}", response);
+        Ok(())
     }
-    Ok(())
+    .with_context(handler_context)
+    .await
+}
+
+/// The identifier of the document of a greeting in Elastic Search: the greeter and the sequence number of its
+/// `GreetedEvent`, so that retracting a greeting leaves identical greetings alone.
+///
+/// Older versions used a hash of the text, see the README on how to rebuild the index when upgrading.
+fn greeting_id(aggregate_identifier: &str, sequence_number: i64) -> String {
+    format!("{}:{}", aggregate_identifier, sequence_number)
 }
@@ -0,0 +1,24 @@
+//! Native gRPC-Web support, so that the browser front-end can reach the services without the Envoy proxy.
+
+use crate::example_config::GrpcWebConfig;
+use std::time::Duration;
+use tonic_web::Config;
+
+/// Creates the gRPC-Web configuration, or `None` if gRPC-Web is disabled.
+///
+/// The settings must have been validated by `GrpcWebConfig::validate`.
+pub fn grpc_web_config(settings: &GrpcWebConfig) -> Option<Config> {
+    if !settings.enabled {
+        return None;
+    }
+    let config = tonic_web::config()
+        .expose_headers(settings.exposed_headers.iter().map(String::as_str))
+        .allow_credentials(settings.allow_credentials)
+        .max_age(Duration::from_secs(settings.max_age_seconds));
+    let config = if settings.allows_all_origins() {
+        config.allow_all_origins()
+    } else {
+        config.allow_origins(settings.allowed_origins.iter().map(String::as_str))
+    };
+    Some(config)
+}
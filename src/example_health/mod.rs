@@ -0,0 +1,150 @@
+//! Serves the standard `grpc.health.v1.Health` service.
+//!
+//! Each back-end component reports its own status under its own service name. The overall status (service name
+//! `""`) and the status of the application services are `SERVING` only when all components are serving. Once the
+//! application starts shutting down, every status is `NOT_SERVING`.
+
+use crate::example_config::ApplicationConfig;
+use crate::example_metrics::head_token;
+use async_lock::Mutex;
+use dendrite::axon_server::event::event_store_client::EventStoreClient;
+use dendrite::axon_server::event::GetLastTokenRequest;
+use dendrite::axon_utils::AxonServerHandle;
+use lazy_static::lazy_static;
+use log::{debug, info};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic_health::proto::health_server::{Health, HealthServer};
+use tonic_health::server::{health_reporter, HealthReporter};
+use tonic_health::ServingStatus;
+
+/// The names of the application services that are reported with the overall status.
+const APPLICATION_SERVICES: &[&str] = &[
+    "proto_example.GreeterService",
+    "proto_dendrite_config.ConfigurationService",
+];
+
+/// A back-end component that has its own health status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Component {
+    AxonServer,
+    CommandHandler,
+    EventProcessor,
+    QueryProcessor,
+}
+
+impl Component {
+    const ALL: &'static [Component] = &[
+        Component::AxonServer,
+        Component::CommandHandler,
+        Component::EventProcessor,
+        Component::QueryProcessor,
+    ];
+
+    /// The service name under which the status of this component is reported.
+    pub fn service_name(&self) -> &'static str {
+        match self {
+            Component::AxonServer => "dendrite_example.AxonServer",
+            Component::CommandHandler => "dendrite_example.CommandHandler",
+            Component::EventProcessor => "dendrite_example.EventProcessor",
+            Component::QueryProcessor => "dendrite_example.QueryProcessor",
+        }
+    }
+}
+
+#[derive(Default)]
+struct HealthState {
+    reporter: Option<HealthReporter>,
+    serving: BTreeMap<Component, bool>,
+    shutting_down: bool,
+}
+
+lazy_static! {
+    static ref HEALTH: Mutex<HealthState> = Mutex::new(HealthState::default());
+}
+
+/// Creates the `Health` service. Statuses that were reported before are published right away.
+pub async fn health_service() -> HealthServer<impl Health> {
+    let (reporter, service) = health_reporter();
+    let mut state = HEALTH.lock().await;
+    state.reporter = Some(reporter);
+    state.publish().await;
+    service
+}
+
+/// Reports whether the given component is ready to do its work.
+pub async fn report(component: Component, serving: bool) {
+    let mut state = HEALTH.lock().await;
+    if state.serving.get(&component) != Some(&serving) {
+        info!("Health: {:?}: {}", component, if serving { "serving" } else { "not serving" });
+    }
+    state.serving.insert(component, serving);
+    state.publish().await;
+}
+
+/// Marks all services as `NOT_SERVING`, permanently.
+pub async fn shut_down() {
+    let mut state = HEALTH.lock().await;
+    info!("Health: shutting down");
+    state.shutting_down = true;
+    state.publish().await;
+}
+
+/// Periodically checks the connection to AxonServer and reports the result.
+pub fn monitor_axon_server(axon_server_handle: AxonServerHandle, config: Arc<ApplicationConfig>) {
+    let interval = Duration::from_secs(config.health.probe_interval_seconds);
+    let timeout = Duration::from_secs(config.health.probe_timeout_seconds);
+    tokio::spawn(async move {
+        loop {
+            if HEALTH.lock().await.shutting_down {
+                break;
+            }
+            let mut client = EventStoreClient::new(axon_server_handle.conn.clone());
+            let probe = tokio::time::timeout(timeout, client.get_last_token(GetLastTokenRequest {})).await;
+            if let Ok(Ok(response)) = &probe {
+                head_token(response.get_ref().token);
+            }
+            let serving = matches!(probe, Ok(Ok(_)));
+            if !serving {
+                debug!("AxonServer probe failed: {:?}", probe);
+            }
+            report(Component::AxonServer, serving).await;
+            tokio::time::sleep(interval).await;
+        }
+        debug!("Stopped monitoring AxonServer");
+    });
+}
+
+impl HealthState {
+    fn status(&self, component: &Component) -> ServingStatus {
+        if !self.shutting_down && self.serving.get(component) == Some(&true) {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::NotServing
+        }
+    }
+
+    async fn publish(&mut self) {
+        let statuses: Vec<(&str, ServingStatus)> = Component::ALL
+            .iter()
+            .map(|component| (component.service_name(), self.status(component)))
+            .collect();
+        let overall = if statuses.iter().all(|(_, status)| *status == ServingStatus::Serving) {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::NotServing
+        };
+        let reporter = match self.reporter.as_mut() {
+            Some(reporter) => reporter,
+            None => return,
+        };
+        for (service_name, status) in statuses {
+            reporter.set_service_status(service_name, status).await;
+        }
+        reporter.set_service_status("", overall).await;
+        for service_name in APPLICATION_SERVICES {
+            reporter.set_service_status(*service_name, overall).await;
+        }
+    }
+}
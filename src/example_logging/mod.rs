@@ -0,0 +1,178 @@
+//! Logging, either as human-readable text or as JSON lines.
+//!
+//! The text format is the format of `env_logger`, and it is the default. In the JSON format (`logging.format: json`)
+//! each line is an object with the timestamp, the level, the target and the message, and the fields that are known in
+//! the current OpenTelemetry context: `worker`, `method`, `aggregate_id`, `message_name`, `correlation_id` and
+//! `user`. Both formats honor `RUST_LOG`.
+//!
+//! JWTs are redacted from the messages in either format.
+
+use crate::example_config::LogFormat;
+use crate::example_correlation::Correlation;
+use anyhow::{anyhow, Result};
+use log::{Log, Metadata, Record};
+use opentelemetry::Context;
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+const JWT_PREFIX: &str = "eyJ";
+const REDACTED: &str = "[REDACTED]";
+
+static JSON_FORMAT: AtomicBool = AtomicBool::new(false);
+
+/// The fields of the JSON log lines that describe what is being worked on.
+#[derive(Debug, Clone, Default)]
+pub struct LogFields {
+    pub worker: Option<String>,
+    pub method: Option<String>,
+    pub aggregate_id: Option<String>,
+    pub message_name: Option<String>,
+}
+
+impl LogFields {
+    /// The log fields that are stored in the given context.
+    pub fn from_context(context: &Context) -> LogFields {
+        context.get::<LogFields>().cloned().unwrap_or_default()
+    }
+}
+
+/// Installs the logger, in text format.
+///
+/// Call this before anything is logged. The format can be changed later with function `set_log_format`.
+pub fn init_logging() -> Result<()> {
+    let text_logger = env_logger::Builder::from_default_env().build();
+    let max_level = text_logger.filter();
+    log::set_boxed_logger(Box::new(ExampleLogger { text_logger }))
+        .map_err(|e| anyhow!("Can't install logger: {:?}", e))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+/// Selects the format of the log lines that are written from now on.
+pub fn set_log_format(format: LogFormat) {
+    JSON_FORMAT.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+/// The given context, with the label of the worker as log field.
+pub fn with_worker(context: &Context, worker: &str) -> Context {
+    with_log_fields(context, |fields| fields.worker = Some(worker.to_string()))
+}
+
+/// The given context, with the gRPC method as log field.
+pub fn with_method(context: &Context, method: &str) -> Context {
+    with_log_fields(context, |fields| fields.method = Some(method.to_string()))
+}
+
+/// The given context, with the name of the command, event or query and the aggregate identifier as log fields.
+pub fn with_message(context: &Context, message_name: &str, aggregate_id: Option<&str>) -> Context {
+    with_log_fields(context, |fields| {
+        fields.message_name = Some(message_name.to_string());
+        if let Some(aggregate_id) = aggregate_id {
+            fields.aggregate_id = Some(aggregate_id.to_string());
+        }
+    })
+}
+
+fn with_log_fields<F>(context: &Context, update: F) -> Context
+where
+    F: FnOnce(&mut LogFields),
+{
+    let mut fields = LogFields::from_context(context);
+    update(&mut fields);
+    context.with_value(fields)
+}
+
+struct ExampleLogger {
+    text_logger: env_logger::Logger,
+}
+
+impl Log for ExampleLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.text_logger.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.text_logger.matches(record) {
+            return;
+        }
+        let message = record.args().to_string();
+        let message = redact(&message);
+        if JSON_FORMAT.load(Ordering::Relaxed) {
+            let line = json_line(record, &message);
+            let stderr = io::stderr();
+            let mut stderr = stderr.lock();
+            writeln!(stderr, "{}", line).ok();
+        } else {
+            self.text_logger.log(
+                &Record::builder()
+                    .args(format_args!("{}", message))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            );
+        }
+    }
+
+    fn flush(&self) {
+        self.text_logger.flush();
+    }
+}
+
+fn json_line(record: &Record, message: &str) -> Value {
+    let mut line = Map::new();
+    line.insert(
+        "timestamp".to_string(),
+        Value::from(humantime::format_rfc3339_millis(SystemTime::now()).to_string()),
+    );
+    line.insert("level".to_string(), Value::from(record.level().as_str()));
+    line.insert("target".to_string(), Value::from(record.target()));
+    line.insert("message".to_string(), Value::from(message));
+    let context = Context::current();
+    let fields = LogFields::from_context(&context);
+    let correlation = Correlation::from_context(&context);
+    for (key, value) in [
+        ("worker", fields.worker),
+        ("method", fields.method),
+        ("aggregate_id", fields.aggregate_id),
+        ("message_name", fields.message_name),
+        ("correlation_id", correlation.as_ref().map(|c| c.correlation_id.clone())),
+        ("user", correlation.and_then(|c| c.user)),
+    ] {
+        if let Some(value) = value {
+            line.insert(key.to_string(), Value::from(value));
+        }
+    }
+    Value::Object(line)
+}
+
+/// Replaces everything that looks like a JWT, _i.e._, three base64url segments that start with `eyJ`.
+fn redact(message: &str) -> Cow<'_, str> {
+    if !message.contains(JWT_PREFIX) {
+        return Cow::Borrowed(message);
+    }
+    let mut redacted = String::with_capacity(message.len());
+    let mut rest = message;
+    while let Some(start) = rest.find(|c: char| is_token_char(c)) {
+        redacted.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = rest.find(|c: char| !is_token_char(c)).unwrap_or(rest.len());
+        let token = &rest[..end];
+        if token.starts_with(JWT_PREFIX) && token.split('.').count() == 3 {
+            redacted.push_str(REDACTED);
+        } else {
+            redacted.push_str(token);
+        }
+        rest = &rest[end..];
+    }
+    redacted.push_str(rest);
+    Cow::Owned(redacted)
+}
+
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.'
+}
@@ -0,0 +1,197 @@
+//! Prometheus metrics, served on `/metrics`.
+//!
+//! | Metric                                                 | Labels           |
+//! |--------------------------------------------------------|------------------|
+//! | `dendrite_example_grpc_requests_total`                 | `method`, `code` |
+//! | `dendrite_example_grpc_request_duration_seconds`       | `method`         |
+//! | `dendrite_example_command_duration_seconds`            | `command`        |
+//! | `dendrite_example_command_failures_total`              | `command`        |
+//! | `dendrite_example_events_handled_total`                | `type`           |
+//! | `dendrite_example_event_processor_token_lag`           |                  |
+//! | `dendrite_example_elastic_search_duration_seconds`     | `operation`      |
+//! | `dendrite_example_worker_up`                           | `worker`         |
+//! | `dendrite_example_worker_restarts_total`               | `worker`         |
+//!
+//! The duration of a streaming RPC is the time until the stream is ready, not the time until it ends. The token lag
+//! is the difference between the head of the event store, as seen by the health probe, and the last token that was
+//! stored by the event processor.
+
+use anyhow::{Context, Result};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use lazy_static::lazy_static;
+use log::{error, info};
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, register_int_gauge_vec, Encoder,
+    HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Instant;
+use tonic::Status;
+
+lazy_static! {
+    static ref GRPC_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "dendrite_example_grpc_requests_total",
+        "Number of GreeterService requests, by method and status code",
+        &["method", "code"]
+    )
+    .unwrap();
+    static ref GRPC_DURATION: HistogramVec = register_histogram_vec!(
+        "dendrite_example_grpc_request_duration_seconds",
+        "Duration of GreeterService requests, by method",
+        &["method"]
+    )
+    .unwrap();
+    static ref COMMAND_DURATION: HistogramVec = register_histogram_vec!(
+        "dendrite_example_command_duration_seconds",
+        "Duration of command dispatch, by command name",
+        &["command"]
+    )
+    .unwrap();
+    static ref COMMAND_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "dendrite_example_command_failures_total",
+        "Number of commands that failed, by command name",
+        &["command"]
+    )
+    .unwrap();
+    static ref EVENTS_HANDLED: IntCounterVec = register_int_counter_vec!(
+        "dendrite_example_events_handled_total",
+        "Number of events that were handled by the event processor, by event type",
+        &["type"]
+    )
+    .unwrap();
+    static ref TOKEN_LAG: IntGauge = register_int_gauge!(
+        "dendrite_example_event_processor_token_lag",
+        "Number of events in the event store that the event processor has not processed yet"
+    )
+    .unwrap();
+    static ref ELASTIC_SEARCH_DURATION: HistogramVec = register_histogram_vec!(
+        "dendrite_example_elastic_search_duration_seconds",
+        "Duration of Elastic Search requests, by operation",
+        &["operation"]
+    )
+    .unwrap();
+    static ref WORKER_UP: IntGaugeVec = register_int_gauge_vec!(
+        "dendrite_example_worker_up",
+        "Whether a worker is running (1) or not (0), by label",
+        &["worker"]
+    )
+    .unwrap();
+    static ref WORKER_RESTARTS: IntCounterVec = register_int_counter_vec!(
+        "dendrite_example_worker_restarts_total",
+        "Number of restarts of a supervised worker, by label",
+        &["worker"]
+    )
+    .unwrap();
+}
+
+static HEAD_TOKEN: AtomicI64 = AtomicI64::new(-1);
+static PROCESSED_TOKEN: AtomicI64 = AtomicI64::new(-1);
+
+/// Records the outcome and the duration of a `GreeterService` RPC.
+pub async fn observe_rpc<T, F>(method: &str, call: F) -> Result<T, Status>
+where
+    F: Future<Output = Result<T, Status>>,
+{
+    let start = Instant::now();
+    let result = call.await;
+    let code = match &result {
+        Ok(_) => tonic::Code::Ok,
+        Err(status) => status.code(),
+    };
+    GRPC_DURATION
+        .with_label_values(&[method])
+        .observe(start.elapsed().as_secs_f64());
+    GRPC_REQUESTS
+        .with_label_values(&[method, &format!("{:?}", code)])
+        .inc();
+    result
+}
+
+/// Records the duration of a command dispatch and whether it failed.
+pub async fn observe_command<T, F>(command: &str, dispatch: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    let start = Instant::now();
+    let result = dispatch.await;
+    COMMAND_DURATION
+        .with_label_values(&[command])
+        .observe(start.elapsed().as_secs_f64());
+    if result.is_err() {
+        COMMAND_FAILURES.with_label_values(&[command]).inc();
+    }
+    result
+}
+
+/// Records the duration of an Elastic Search request.
+pub async fn observe_elastic_search<T, F>(operation: &str, request: F) -> T
+where
+    F: Future<Output = T>,
+{
+    let start = Instant::now();
+    let result = request.await;
+    ELASTIC_SEARCH_DURATION
+        .with_label_values(&[operation])
+        .observe(start.elapsed().as_secs_f64());
+    result
+}
+
+/// Counts an event that was handled by the event processor.
+pub fn event_handled(event_type: &str) {
+    EVENTS_HANDLED.with_label_values(&[event_type]).inc();
+}
+
+/// Records the last token in the event store.
+pub fn head_token(token: i64) {
+    HEAD_TOKEN.store(token, Ordering::Relaxed);
+}
+
+/// Records the last token that was stored by the event processor.
+pub fn processed_token(token: i64) {
+    PROCESSED_TOKEN.store(token, Ordering::Relaxed);
+}
+
+/// Records whether the worker with the given label is running.
+pub fn worker_up(label: &str, up: bool) {
+    WORKER_UP.with_label_values(&[label]).set(up as i64);
+}
+
+/// Counts a restart of the supervised worker with the given label.
+pub fn worker_restarted(label: &str) {
+    WORKER_RESTARTS.with_label_values(&[label]).inc();
+}
+
+/// Serves `/metrics` on the given address until `shutdown` completes.
+pub async fn serve<F>(address: SocketAddr, shutdown: F) -> Result<()>
+where
+    F: Future<Output = ()>,
+{
+    info!("Starting metrics endpoint: {:?}", address);
+    let router = Router::new().route("/metrics", get(metrics));
+    axum::Server::try_bind(&address)
+        .with_context(|| format!("Can't bind metrics endpoint to: {:?}", address))?
+        .serve(router.into_make_service())
+        .with_graceful_shutdown(shutdown)
+        .await
+        .context("Error in metrics endpoint")
+}
+
+async fn metrics() -> impl IntoResponse {
+    let head_token = HEAD_TOKEN.load(Ordering::Relaxed);
+    if head_token >= 0 {
+        TOKEN_LAG.set((head_token - PROCESSED_TOKEN.load(Ordering::Relaxed)).max(0));
+    }
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        error!("Error while encoding metrics: {:?}", e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, [(CONTENT_TYPE, "text/plain".to_string())], Vec::new());
+    }
+    (StatusCode::OK, [(CONTENT_TYPE, encoder.format_type().to_string())], buffer)
+}
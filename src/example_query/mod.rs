@@ -1,35 +1,49 @@
+use crate::example_config::ApplicationConfig;
+use crate::example_correlation::query_context;
+use crate::example_health::{report, Component};
+use crate::example_logging::with_message;
+use crate::example_metrics::observe_elastic_search;
+use crate::example_supervisor::supervise;
+use crate::example_tracing::child_context;
 use crate::proto_example::{Greeting, SearchQuery, SearchResponse};
 use anyhow::{Context, Result};
 use dendrite::axon_server::query::QueryRequest;
-use dendrite::axon_utils::{axon_serialize, empty_handler_registry, query_processor, AxonServerHandle, HandlerRegistry, QueryContext, QueryResult, TheHandlerRegistry, WorkerControl};
+use dendrite::axon_utils::{axon_serialize, empty_handler_registry, query_processor, AxonServerHandle, HandlerRegistry, QueryContext, QueryResult, TheHandlerRegistry, WorkerControl, WorkerThread};
 use dendrite::elasticsearch::wait_for_elastic_search;
 use dendrite::macros as dendrite_macros;
 use elasticsearch::{Elasticsearch, SearchParts};
-use log::{debug, error};
+use log::debug;
+use opentelemetry::trace::{FutureExt, SpanKind};
 use prost::Message;
+use std::sync::Arc;
 
 #[derive(Clone)]
 struct ExampleQueryContext {
     es_client: Elasticsearch,
+    greetings_index: String,
 }
 
 impl QueryContext for ExampleQueryContext {}
 
+/// Creates a supervised worker that handles queries with the given configuration.
+pub fn process_queries_with(config: Arc<ApplicationConfig>) -> WorkerThread {
+    let worker_config = config.clone();
+    supervise("query", &config, move |handle, worker_control| {
+        Box::pin(internal_process_queries(handle, worker_config.clone(), worker_control))
+    })
+}
+
 /// Handles queries.
 ///
 /// Constructs an query handler registry and delegates to function `query_processor`.
-pub async fn process_queries(axon_server_handle: AxonServerHandle, worker_control: WorkerControl) {
-    if let Err(e) = internal_process_queries(axon_server_handle, worker_control).await {
-        error!("Error while handling queries: {:?}", e);
-    }
-    debug!("Stopped handling queries");
-}
-
-async fn internal_process_queries(axon_server_handle: AxonServerHandle, worker_control: WorkerControl) -> Result<()> {
+async fn internal_process_queries(axon_server_handle: AxonServerHandle, config: Arc<ApplicationConfig>, worker_control: WorkerControl) -> Result<()> {
     let client = wait_for_elastic_search().await?;
     debug!("Elastic Search client: {:?}", client);
 
-    let query_context = ExampleQueryContext { es_client: client };
+    let query_context = ExampleQueryContext {
+        es_client: client,
+        greetings_index: config.elastic_search.greetings_index.clone(),
+    };
 
     let mut query_handler_registry: TheHandlerRegistry<
         ExampleQueryContext,
@@ -39,46 +53,63 @@ async fn internal_process_queries(axon_server_handle: AxonServerHandle, worker_c
 
     query_handler_registry.register(&handle_search_query)?;
 
-    query_processor(axon_server_handle, query_context, query_handler_registry, worker_control)
+    report(Component::QueryProcessor, true).await;
+    let result = query_processor(axon_server_handle, query_context, query_handler_registry, worker_control)
         .await
-        .context("Error while handling queries")
+        .context("Error while handling queries");
+    report(Component::QueryProcessor, false).await;
+    result
 }
 
 #[dendrite_macros::query_handler]
 async fn handle_search_query(
     search_query: SearchQuery,
     query_model: ExampleQueryContext,
+    query_request: QueryRequest,
 ) -> Result<Option<QueryResult>> {
-    let search_response = query_model
-        .es_client
-        .search(SearchParts::Index(&["greetings"]))
-        .q(&search_query.query)
-        ._source(&["value"])
-        .send()
-        .await?;
-    let json_value: serde_json::Value = search_response.json().await?;
-    debug!("Search response: {:?}", json_value);
-    let hits = &json_value["hits"]["hits"];
-    debug!("Hits: {:?}", hits);
-    let mut greetings = Vec::new();
-    if let serde_json::Value::Array(hits) = hits {
-        for document in hits {
-            if let serde_json::Value::String(message) = &document["_source"]["value"] {
-                let greeting = Greeting {
-                    message: message.to_string(),
-                };
-                greetings.push(greeting);
+    let context = with_message(
+        &child_context("handle_search_query", SpanKind::Consumer, &query_context(&query_request)),
+        "SearchQuery",
+        None,
+    );
+    async move {
+        let indices = [query_model.greetings_index.as_str()];
+        let request = query_model
+            .es_client
+            .search(SearchParts::Index(&indices))
+            .q(&search_query.query)
+            ._source(&["value"])
+            .send();
+        let elastic_search_span = child_context("Elastic Search search", SpanKind::Client, &opentelemetry::Context::current());
+        let search_response = observe_elastic_search("search", request.with_context(elastic_search_span)).await?;
+        let json_value: serde_json::Value = search_response.json().await?;
+        debug!("Search response: {:?}", json_value);
+        let hits = &json_value["hits"]["hits"];
+        debug!("Hits: {:?}", hits);
+        let mut greetings = Vec::new();
+        if let serde_json::Value::Array(hits) = hits {
+            for document in hits {
+                if let serde_json::Value::String(message) = &document["_source"]["value"] {
+                    let greeting = Greeting {
+                        message: message.to_string(),
+                        ..Greeting::default()
+                    };
+                    greetings.push(greeting);
+                }
             }
         }
+        let greeting = Greeting {
+            message: "Test!".to_string(),
+            ..Greeting::default()
+        };
+        greetings.push(greeting);
+        let response = SearchResponse { greetings };
+        let result = axon_serialize("SearchResponse", &response)?;
+        let query_result = QueryResult {
+            payload: Some(result),
+        };
+        Ok(Some(query_result))
     }
-    let greeting = Greeting {
-        message: "Test!".to_string(),
-    };
-    greetings.push(greeting);
-    let response = SearchResponse { greetings };
-    let result = axon_serialize("SearchResponse", &response)?;
-    let query_result = QueryResult {
-        payload: Some(result),
-    };
-    Ok(Some(query_result))
+    .with_context(context)
+    .await
 }
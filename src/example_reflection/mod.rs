@@ -0,0 +1,15 @@
+use anyhow::Result;
+use tonic_reflection::server::{Builder, ServerReflection, ServerReflectionServer};
+
+/// The file descriptors of `proto_example` and `proto_dendrite_config`, as generated by `build.rs`.
+pub const FILE_DESCRIPTOR_SET: &[u8] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/dendrite_example_descriptor.bin"));
+
+/// Creates the gRPC reflection service for the services of this application.
+pub fn reflection_service() -> Result<ServerReflectionServer<impl ServerReflection>> {
+    let service = Builder::configure()
+        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::proto::GRPC_HEALTH_V1_FILE_DESCRIPTOR_SET)
+        .build()?;
+    Ok(service)
+}
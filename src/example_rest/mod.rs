@@ -0,0 +1,258 @@
+//! HTTP/JSON gateway for the `GreeterService`.
+//!
+//! | Method and path                | RPC         | Response                                        |
+//! |--------------------------------|-------------|-------------------------------------------------|
+//! | `POST /v1/greetings`           | `Greet`     | `Acknowledgement`                               |
+//! | `GET /v1/greetings`            | `Greetings` | array of `Greeting`                             |
+//! | `GET /v1/greetings?follow=true`| `Greetings` | server-sent events `greeting` with a `Greeting` |
+//! | `POST /v1/record`              | `Record`    | `GreeterVersion`                                |
+//! | `POST /v1/stop`                | `Stop`      | `GreeterVersion`                                |
+//! | `POST /v1/retract`             | `Retract`   | `Empty`                                         |
+//! | `GET /v1/search?q=...`         | `Search`    | array of `Greeting`                             |
+//!
+//! Every request is handled by the same `GreeterServer` as the gRPC requests. The `Authorization` header is
+//! verified by the same interceptor, and a `Status` is mapped to the corresponding HTTP status code with a JSON body
+//! that contains the gRPC code, the message and the `google.rpc.ErrorInfo` details.
+
+use crate::example_api::GreeterServer;
+use crate::example_auth::authenticate;
+use crate::example_error::error_infos;
+use crate::proto_example::greeter_service_server::GreeterService;
+use crate::proto_example::{GreeterReference, Greeting, GreetingsMode, GreetingsRequest, RetractRequest, SearchQuery};
+use anyhow::{Context, Result};
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use log::{debug, info};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::{Infallible, TryFrom};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tonic::metadata::MetadataValue;
+use tonic::{Code, Request, Status};
+
+const FORWARDED_HEADERS: &[&str] = &["authorization", "traceparent", "tracestate", "x-request-id"];
+
+/// Query parameters of `GET /v1/greetings`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct GreetingsParameters {
+    pub greeter_id: String,
+    pub follow: bool,
+}
+
+/// Query parameters of `GET /v1/search`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SearchParameters {
+    pub q: String,
+}
+
+/// A `Status` that is returned as an HTTP error response.
+#[derive(Debug)]
+pub struct ApiError(Status);
+
+/// Creates the routes of the gateway.
+pub fn router(greeter_server: Arc<GreeterServer>) -> Router {
+    Router::new()
+        .route("/v1/greetings", post(greet).get(greetings))
+        .route("/v1/record", post(record))
+        .route("/v1/stop", post(stop))
+        .route("/v1/retract", post(retract))
+        .route("/v1/search", get(search))
+        .with_state(greeter_server)
+}
+
+/// Serves the gateway on the given address until `shutdown` completes.
+pub async fn serve<F>(address: SocketAddr, greeter_server: Arc<GreeterServer>, shutdown: F) -> Result<()>
+where
+    F: Future<Output = ()>,
+{
+    info!("Starting REST gateway: {:?}", address);
+    axum::Server::try_bind(&address)
+        .with_context(|| format!("Can't bind REST gateway to: {:?}", address))?
+        .serve(router(greeter_server).into_make_service())
+        .with_graceful_shutdown(shutdown)
+        .await
+        .context("Error in REST gateway")
+}
+
+async fn greet(
+    State(greeter_server): State<Arc<GreeterServer>>,
+    headers: HeaderMap,
+    Json(greeting): Json<Greeting>,
+) -> Result<impl IntoResponse, ApiError> {
+    let request = grpc_request(&headers, greeting)?;
+    let response = greeter_server.greet(request).await?;
+    Ok(Json(response.into_inner()))
+}
+
+async fn record(
+    State(greeter_server): State<Arc<GreeterServer>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<impl IntoResponse, ApiError> {
+    let reference: GreeterReference = optional_json(&body)?;
+    let request = grpc_request(&headers, reference)?;
+    let response = greeter_server.record(request).await?;
+    Ok(Json(response.into_inner()))
+}
+
+async fn stop(
+    State(greeter_server): State<Arc<GreeterServer>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<impl IntoResponse, ApiError> {
+    let reference: GreeterReference = optional_json(&body)?;
+    let request = grpc_request(&headers, reference)?;
+    let response = greeter_server.stop(request).await?;
+    Ok(Json(response.into_inner()))
+}
+
+async fn retract(
+    State(greeter_server): State<Arc<GreeterServer>>,
+    headers: HeaderMap,
+    Json(retract_request): Json<RetractRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let request = grpc_request(&headers, retract_request)?;
+    let response = greeter_server.retract(request).await?;
+    Ok(Json(response.into_inner()))
+}
+
+async fn greetings(
+    State(greeter_server): State<Arc<GreeterServer>>,
+    headers: HeaderMap,
+    Query(parameters): Query<GreetingsParameters>,
+) -> Result<Response, ApiError> {
+    let mode = if parameters.follow {
+        GreetingsMode::ReplayThenFollow
+    } else {
+        GreetingsMode::ReplayOnly
+    };
+    let greetings_request = GreetingsRequest {
+        greeter_id: parameters.greeter_id,
+        mode: mode as i32,
+    };
+    let request = grpc_request(&headers, greetings_request)?;
+    let stream = greeter_server.greetings(request).await?.into_inner();
+    if parameters.follow {
+        return Ok(Sse::new(greeting_events(stream))
+            .keep_alive(KeepAlive::default())
+            .into_response());
+    }
+    Ok(Json(collect(stream).await?).into_response())
+}
+
+async fn search(
+    State(greeter_server): State<Arc<GreeterServer>>,
+    headers: HeaderMap,
+    Query(parameters): Query<SearchParameters>,
+) -> Result<impl IntoResponse, ApiError> {
+    let request = grpc_request(&headers, SearchQuery { query: parameters.q })?;
+    let stream = greeter_server.search(request).await?.into_inner();
+    Ok(Json(collect(stream).await?))
+}
+
+/// Wraps a message in a `tonic::Request` that has passed the same authentication as a gRPC request.
+///
+/// The authorization, trace context and request id headers are copied to the metadata of the request.
+fn grpc_request<T>(headers: &HeaderMap, message: T) -> Result<Request<T>, Status> {
+    let mut request = Request::new(());
+    for name in FORWARDED_HEADERS {
+        if let Some(header) = headers.get(*name) {
+            let value = MetadataValue::try_from(header.as_bytes())
+                .map_err(|_| Status::unauthenticated(format!("Malformed {} header", name)))?;
+            request.metadata_mut().insert(*name, value);
+        }
+    }
+    let (metadata, extensions, ()) = authenticate(request)?.into_parts();
+    Ok(Request::from_parts(metadata, extensions, message))
+}
+
+/// Parses an optional JSON body: an empty body means the default value, but a malformed body is rejected.
+fn optional_json<T: DeserializeOwned + Default>(body: &Bytes) -> Result<T, Status> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(T::default());
+    }
+    serde_json::from_slice(body).map_err(|e| Status::invalid_argument(format!("Malformed JSON body: {}", e)))
+}
+
+async fn collect<S>(mut stream: S) -> Result<Vec<Greeting>, Status>
+where
+    S: Stream<Item = Result<Greeting, Status>> + Unpin,
+{
+    let mut greetings = Vec::new();
+    while let Some(greeting) = stream.next().await {
+        greetings.push(greeting?);
+    }
+    Ok(greetings)
+}
+
+fn greeting_events<S>(stream: S) -> impl Stream<Item = Result<Event, Infallible>>
+where
+    S: Stream<Item = Result<Greeting, Status>>,
+{
+    stream.map(|greeting| {
+        let event = match greeting {
+            Ok(greeting) => Event::default().event("greeting").json_data(greeting),
+            Err(status) => Event::default().event("error").json_data(error_body(&status)),
+        };
+        Ok(event.unwrap_or_else(|e| Event::default().event("error").data(e.to_string())))
+    })
+}
+
+impl From<Status> for ApiError {
+    fn from(status: Status) -> Self {
+        ApiError(status)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        debug!("REST error: {:?}", self.0);
+        (http_status(self.0.code()), Json(error_body(&self.0))).into_response()
+    }
+}
+
+/// The HTTP status code that corresponds to a gRPC status code.
+pub fn http_status(code: Code) -> StatusCode {
+    match code {
+        Code::Ok => StatusCode::OK,
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => StatusCode::BAD_REQUEST,
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        Code::Cancelled | Code::Unknown | Code::Internal | Code::DataLoss => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn error_body(status: &Status) -> Value {
+    let details: Vec<Value> = error_infos(status)
+        .into_iter()
+        .map(|error_info| {
+            json!({
+                "reason": error_info.reason,
+                "domain": error_info.domain,
+                "metadata": error_info.metadata,
+            })
+        })
+        .collect();
+    json!({
+        "code": format!("{:?}", status.code()),
+        "message": status.message(),
+        "details": details,
+    })
+}
@@ -0,0 +1,140 @@
+//! Graceful shutdown on SIGTERM and SIGINT.
+//!
+//! The shutdown sequence is:
+//! 1. All health statuses become `NOT_SERVING`.
+//! 2. Live tails of the `Greetings` stream end, and the gRPC, REST and metrics listeners stop accepting connections.
+//!    Requests that are in flight get `shutdown.grace_period_seconds` to complete.
+//! 3. The workers stop one by one, in the order of `STOP_ORDER`: first the command and query handlers, then the event
+//!    processors, and the connection to the AxonServer platform last. Each worker gets the same grace period. The
+//!    event processor flushes its tracking token when it stops.
+//!
+//! Workers take part in the sequence by registering for a `ShutdownTurn`. When a worker stops by itself, the other
+//! workers are stopped by `AxonServerHandle::join_workers`, without this sequence.
+
+use crate::example_config::ShutdownConfig;
+use crate::example_health::shut_down;
+use anyhow::Result;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{oneshot, watch};
+use tokio::time::timeout;
+
+/// The keys of the workers, in the order in which they are stopped.
+///
+/// The keys are the names of the fields in `WorkerLabels`.
+const STOP_ORDER: &[&str] = &[
+    "grpc_server",
+    "command",
+    "trust_command",
+    "query",
+    "event",
+    "replica",
+    "auth",
+    "platform",
+];
+
+type Turn = (watch::Sender<bool>, oneshot::Receiver<()>);
+
+lazy_static! {
+    static ref DRAINING: watch::Sender<bool> = watch::channel(false).0;
+    static ref TURNS: Mutex<HashMap<String, Turn>> = Mutex::new(HashMap::new());
+}
+
+/// The place of a worker in the shutdown sequence.
+///
+/// The sequence proceeds to the next worker when the turn is dropped.
+pub struct ShutdownTurn {
+    requested: watch::Receiver<bool>,
+    _stopped: oneshot::Sender<()>,
+}
+
+impl ShutdownTurn {
+    /// Completes when it is the turn of the worker to stop.
+    pub fn requested(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut requested = self.requested.clone();
+        async move {
+            while !*requested.borrow() {
+                if requested.changed().await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Registers the worker with the given key for a turn in the shutdown sequence.
+pub fn register(key: &str) -> ShutdownTurn {
+    let (request_tx, request_rx) = watch::channel(false);
+    let (stopped_tx, stopped_rx) = oneshot::channel();
+    match TURNS.lock() {
+        Ok(mut turns) => {
+            turns.insert(key.to_string(), (request_tx, stopped_rx));
+        }
+        Err(e) => warn!("Can't register for shutdown: {:?}: {:?}", key, e),
+    }
+    ShutdownTurn {
+        requested: request_rx,
+        _stopped: stopped_tx,
+    }
+}
+
+/// Listens for SIGTERM and SIGINT, and returns a future that completes with the name of the first one that arrives.
+///
+/// The signals are intercepted from the moment this function is called.
+pub fn shutdown_signal() -> Result<impl Future<Output = &'static str>> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    Ok(async move {
+        select! {
+            _ = terminate.recv() => "SIGTERM",
+            _ = interrupt.recv() => "SIGINT",
+        }
+    })
+}
+
+/// Completes when the shutdown sequence has started.
+pub async fn draining() {
+    let mut draining = DRAINING.subscribe();
+    while !*draining.borrow() {
+        if draining.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Runs the shutdown sequence.
+pub async fn graceful_shutdown(config: &ShutdownConfig) {
+    shut_down().await;
+    DRAINING.send_replace(true);
+    let grace_period = Duration::from_secs(config.grace_period_seconds);
+    let mut turns = match TURNS.lock() {
+        Ok(mut turns) => std::mem::take(&mut *turns),
+        Err(e) => {
+            warn!("Can't read shutdown registrations: {:?}", e);
+            HashMap::new()
+        }
+    };
+    for key in STOP_ORDER {
+        if let Some(turn) = turns.remove(*key) {
+            stop_worker(key, turn, grace_period).await;
+        }
+    }
+    for (key, turn) in turns {
+        stop_worker(&key, turn, grace_period).await;
+    }
+    info!("Shutdown complete");
+}
+
+async fn stop_worker(key: &str, (request, stopped): Turn, grace_period: Duration) {
+    info!("Stopping worker: {:?}", key);
+    request.send_replace(true);
+    if timeout(grace_period, stopped).await.is_err() {
+        warn!("Worker did not stop within the grace period: {:?}: {:?}", key, grace_period);
+    }
+}
@@ -0,0 +1,203 @@
+//! Supervision of workers that are spawned on the `AxonServerHandle`.
+//!
+//! A supervised worker runs its task as a nested worker, and spawns a fresh one when the task stops, according to
+//! the restart policy of the worker. Restarts are delayed with exponential backoff and jitter. When more than
+//! `supervision.max_restarts` restarts occur within `supervision.restart_window_seconds`, the supervisor gives up
+//! and the whole application shuts down, just like it does when an unsupervised worker stops.
+//!
+//! Each worker needs its own `WorkerControl`, which can only be obtained from `AxonServerHandle::spawn`. The
+//! termination notifications of the nested workers are diverted to the supervisor, and only handed over to the
+//! `AxonServerHandle` when the supervisor itself stops.
+//!
+//! During a graceful shutdown, the supervisor stops its task in the turn of the worker (see module
+//! `example_shutdown`), by closing the control channel of the nested worker.
+
+use crate::example_config::{ApplicationConfig, RestartPolicy, SupervisionConfig};
+use crate::example_logging::with_worker;
+use crate::example_shutdown::{register, ShutdownTurn};
+use crate::example_metrics::{worker_restarted, worker_up};
+use anyhow::{anyhow, Result};
+use dendrite::axon_utils::{AxonServerHandle, WorkerControl, WorkerThread};
+use lazy_static::lazy_static;
+use log::{debug, error, info, warn};
+use opentelemetry::trace::FutureExt;
+use opentelemetry::Context;
+use rand_core::{OsRng, RngCore};
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::select;
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+lazy_static! {
+    static ref RESTART_COUNTS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+}
+
+/// The future of a single attempt to run a supervised task.
+pub type AttemptFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// Wraps a restartable task in a `WorkerThread` that restarts it according to the supervision settings for `key`.
+///
+/// The key is the name of the field in `WorkerLabels` for the worker, _e.g._, `command`.
+pub fn supervise<F>(key: &str, config: &ApplicationConfig, task: F) -> WorkerThread
+where
+    F: Fn(AxonServerHandle, WorkerControl) -> AttemptFuture + Send + Sync + 'static,
+{
+    let settings = config.supervision.clone();
+    let policy = settings.policy_for(key);
+    let task = Arc::new(task);
+    let key = key.to_string();
+    Box::new(move |handle, worker_control| {
+        let turn = register(&key);
+        Box::pin(supervise_worker(handle, worker_control, turn, settings, policy, task))
+    })
+}
+
+/// The number of restarts of each supervised worker, by label.
+pub fn restart_counts() -> BTreeMap<String, u64> {
+    RESTART_COUNTS
+        .lock()
+        .map(|counts| counts.clone())
+        .unwrap_or_default()
+}
+
+async fn supervise_worker<F>(
+    axon_server_handle: AxonServerHandle,
+    worker_control: WorkerControl,
+    turn: ShutdownTurn,
+    settings: SupervisionConfig,
+    policy: RestartPolicy,
+    task: Arc<F>,
+) where
+    F: Fn(AxonServerHandle, WorkerControl) -> AttemptFuture + Send + Sync + 'static,
+{
+    let label = worker_control.get_label().to_string();
+    let control_channel = worker_control.get_control_channel();
+    debug!("Supervise worker: {:?}: {:?}", label, policy);
+
+    let (notify, _notifications) = async_channel::unbounded();
+    let mut attempt_handle = axon_server_handle.clone();
+    attempt_handle.notify = notify;
+
+    let mut attempt_ids: Vec<Uuid> = Vec::new();
+    let mut restarts: VecDeque<Instant> = VecDeque::new();
+    let mut stopping = false;
+    loop {
+        let (outcome_tx, mut outcome_rx) = oneshot::channel();
+        let (attempt_control_tx, mut attempt_control_rx) = oneshot::channel();
+        let attempt_task = task.clone();
+        let attempt_label = label.clone();
+        let spawned = attempt_handle.spawn(
+            &*label,
+            Box::new(move |handle, worker_control: WorkerControl| async move {
+                attempt_control_tx.send(worker_control.get_control_channel()).ok();
+                let context = with_worker(&Context::current(), &attempt_label);
+                outcome_tx.send(attempt_task(handle, worker_control).with_context(context).await).ok();
+            }),
+        );
+        match spawned {
+            Ok(id) => {
+                attempt_ids.push(id);
+                worker_up(&label, true);
+            }
+            Err(e) => {
+                error!("Error while spawning worker: {:?}: {:?}", label, e);
+                break;
+            }
+        }
+
+        let outcome = loop {
+            select! {
+                outcome = &mut outcome_rx => break outcome.unwrap_or_else(|_| Err(anyhow!("Worker aborted"))),
+                _command = control_channel.recv(), if !stopping => stopping = true,
+                _requested = turn.requested(), if !stopping => {
+                    stopping = true;
+                    if let Ok(attempt_control) = (&mut attempt_control_rx).await {
+                        attempt_control.close();
+                    }
+                }
+            }
+        };
+        worker_up(&label, false);
+        if stopping {
+            break;
+        }
+        let restart = match (&outcome, policy) {
+            (_, RestartPolicy::Never) => false,
+            (_, RestartPolicy::Always) => true,
+            (Err(_), RestartPolicy::OnFailure) => true,
+            (Ok(_), RestartPolicy::OnFailure) => false,
+        };
+        match &outcome {
+            Err(e) => error!("Worker failed: {:?}: {:?}", label, e),
+            Ok(_) => info!("Worker completed: {:?}", label),
+        }
+        if !restart {
+            break;
+        }
+
+        let now = Instant::now();
+        let window = Duration::from_secs(settings.restart_window_seconds);
+        while restarts.front().map(|t| now.duration_since(*t) >= window).unwrap_or(false) {
+            restarts.pop_front();
+        }
+        if restarts.len() >= settings.max_restarts as usize {
+            error!(
+                "Restart budget exhausted: {:?}: {:?} restarts in {:?}: shutting down",
+                label,
+                restarts.len(),
+                window
+            );
+            break;
+        }
+        restarts.push_back(now);
+        let count = record_restart(&label);
+        let delay = backoff(&settings, restarts.len());
+        warn!(
+            "Restarting worker: {:?}: restart {:?} in {:?}",
+            label, count, delay
+        );
+        select! {
+            _ = tokio::time::sleep(delay) => {},
+            _command = control_channel.recv() => break,
+            _requested = turn.requested() => break,
+        }
+    }
+    drop(turn);
+
+    for id in attempt_ids {
+        if let Err(e) = axon_server_handle.notify.send(id).await {
+            debug!("Termination notification failed for attempt: {:?}: {:?}", id, e);
+        }
+    }
+    debug!("Stopped supervising worker: {:?}", label);
+}
+
+fn record_restart(label: &str) -> u64 {
+    worker_restarted(label);
+    match RESTART_COUNTS.lock() {
+        Ok(mut counts) => {
+            let count = counts.entry(label.to_string()).or_insert(0);
+            *count += 1;
+            *count
+        }
+        Err(e) => {
+            error!("Can't record restart: {:?}: {:?}", label, e);
+            0
+        }
+    }
+}
+
+/// The delay before the given restart within the restart window, _i.e._, `initial * 2^(restart - 1)`, capped and
+/// randomly spread by the configured jitter fraction.
+fn backoff(settings: &SupervisionConfig, restart: usize) -> Duration {
+    let exponent = restart.saturating_sub(1).min(32) as i32;
+    let millis = (settings.initial_backoff_millis as f64 * 2f64.powi(exponent))
+        .min(settings.max_backoff_millis as f64);
+    let random = OsRng.next_u32() as f64 / u32::MAX as f64;
+    let spread = 1.0 + settings.jitter * (2.0 * random - 1.0);
+    Duration::from_millis((millis * spread).max(0.0) as u64)
+}
@@ -0,0 +1,170 @@
+//! TLS for the gRPC listener, with optional verification of client certificates.
+//!
+//! The certificate chain, the private key and the client CA bundle are read from the PEM files that are named in
+//! `server.tls`, and read again whenever the process receives SIGHUP. Connections that are accepted after a reload
+//! use the new certificates. Established connections are not affected.
+
+use crate::example_auth::set_certificate_roles;
+use crate::example_config::{ApplicationConfig, TlsConfig};
+use anyhow::{anyhow, Context, Result};
+use futures_core::Stream;
+use lazy_static::lazy_static;
+use log::{debug, error, info, warn};
+use rustls_pemfile::Item;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient};
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+const ALPN_PROTOCOLS: &[&[u8]] = &[b"h2", b"http/1.1"];
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const PENDING_CONNECTIONS: usize = 16;
+
+lazy_static! {
+    static ref SERVER_CONFIG: RwLock<Option<Arc<ServerConfig>>> = RwLock::new(None);
+}
+
+/// Reads the certificates and the key, and uses them for connections that are accepted from now on.
+pub fn load_certificates(config: &TlsConfig) -> Result<()> {
+    let server_config = Arc::new(server_config(config)?);
+    let mut current = SERVER_CONFIG
+        .write()
+        .map_err(|e| anyhow!("Can't set TLS configuration: {:?}", e))?;
+    *current = Some(server_config);
+    set_certificate_roles(config.certificate_roles.clone())?;
+    info!("Loaded TLS certificate: {:?}", config.certificate_file);
+    Ok(())
+}
+
+/// Reloads the certificates whenever the process receives SIGHUP.
+///
+/// If the files can't be read, the previous certificates remain in use.
+pub fn reload_certificates_on_hangup(config: Arc<ApplicationConfig>) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("Reload TLS certificates");
+            if let Err(e) = load_certificates(&config.server.tls) {
+                error!("Error while reloading TLS certificates: {:?}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Accepts TCP connections on the given address and yields those that complete the TLS handshake.
+///
+/// Stops accepting connections when the stream is dropped.
+pub async fn tls_incoming(address: SocketAddr) -> Result<impl Stream<Item = io::Result<TlsStream<TcpStream>>>> {
+    let listener = TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Can't bind gRPC server to: {:?}", address))?;
+    let (tx, mut rx) = mpsc::channel(PENDING_CONNECTIONS);
+    tokio::spawn(async move {
+        loop {
+            let (tcp_stream, peer) = select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Error while accepting connection: {:?}", e);
+                        continue;
+                    }
+                },
+                _ = tx.closed() => break,
+            };
+            let acceptor = match current_acceptor() {
+                Ok(acceptor) => acceptor,
+                Err(e) => {
+                    error!("No TLS configuration: {:?}", e);
+                    continue;
+                }
+            };
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp_stream)).await {
+                    Ok(Ok(tls_stream)) => {
+                        tx.send(tls_stream).await.ok();
+                    }
+                    Ok(Err(e)) => debug!("TLS handshake failed: {:?}: {:?}", peer, e),
+                    Err(_) => debug!("TLS handshake timed out: {:?}", peer),
+                }
+            });
+        }
+        debug!("Stopped accepting TLS connections: {:?}", address);
+    });
+    Ok(async_stream::stream! {
+        while let Some(tls_stream) = rx.recv().await {
+            yield Ok(tls_stream);
+        }
+    })
+}
+
+fn current_acceptor() -> Result<TlsAcceptor> {
+    let current = SERVER_CONFIG
+        .read()
+        .map_err(|e| anyhow!("Can't read TLS configuration: {:?}", e))?;
+    let server_config = current
+        .as_ref()
+        .ok_or_else(|| anyhow!("TLS certificates were not loaded"))?;
+    Ok(TlsAcceptor::from(server_config.clone()))
+}
+
+fn server_config(config: &TlsConfig) -> Result<ServerConfig> {
+    let certificates = read_certificates(&config.certificate_file)?;
+    if certificates.is_empty() {
+        return Err(anyhow!("No certificates in: {:?}", config.certificate_file));
+    }
+    let private_key = read_private_key(&config.key_file)?;
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = if config.client_ca_file.is_empty() {
+        builder.with_no_client_auth()
+    } else {
+        let mut roots = RootCertStore::empty();
+        for certificate in read_certificates(&config.client_ca_file)? {
+            roots
+                .add(&certificate)
+                .with_context(|| format!("Invalid CA certificate in: {:?}", config.client_ca_file))?;
+        }
+        if config.require_client_certificate {
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+        } else {
+            builder.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots))
+        }
+    };
+    let mut server_config = builder
+        .with_single_cert(certificates, private_key)
+        .with_context(|| format!("Invalid certificate or key: {:?}: {:?}", config.certificate_file, config.key_file))?;
+    server_config.alpn_protocols = ALPN_PROTOCOLS.iter().map(|protocol| protocol.to_vec()).collect();
+    Ok(server_config)
+}
+
+fn read_certificates(path: &str) -> Result<Vec<Certificate>> {
+    let mut reader = open(path)?;
+    let certificates = rustls_pemfile::certs(&mut reader).with_context(|| format!("Can't read certificates: {:?}", path))?;
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+fn read_private_key(path: &str) -> Result<PrivateKey> {
+    let mut reader = open(path)?;
+    loop {
+        match rustls_pemfile::read_one(&mut reader).with_context(|| format!("Can't read private key: {:?}", path))? {
+            Some(Item::RSAKey(key)) | Some(Item::PKCS8Key(key)) | Some(Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(anyhow!("No private key in: {:?}", path)),
+        }
+    }
+}
+
+fn open(path: &str) -> Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("Can't open file: {:?}", path))?;
+    Ok(BufReader::new(file))
+}
@@ -0,0 +1,117 @@
+//! Distributed tracing with OpenTelemetry.
+//!
+//! The W3C trace context (`traceparent` and `tracestate`) of an incoming gRPC request becomes the parent of the span
+//! of the RPC. Commands, queries and events carry the trace context along with their correlation id (see module
+//! `example_correlation`), and the command, query and event handlers continue the trace from there.
+//!
+//! Spans are exported according to `tracing.exporter`: not at all, to standard output, or over OTLP/gRPC.
+
+use crate::example_config::{TraceExporter, TracingConfig};
+use crate::example_logging::with_method;
+use log::info;
+use opentelemetry::global;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::trace::config as trace_config;
+use opentelemetry::sdk::Resource;
+use opentelemetry::trace::{FutureExt, SpanKind, Status as SpanStatus, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use std::collections::HashMap;
+use std::future::Future;
+use tonic::metadata::MetadataMap;
+use tonic::Status;
+
+const TRACER_NAME: &str = "dendrite_example";
+
+/// Installs the exporter that is selected by the configuration, and the W3C trace context propagator.
+pub fn init_tracing(config: &TracingConfig) -> anyhow::Result<()> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let resource = Resource::new(vec![KeyValue::new("service.name", config.service_name.clone())]);
+    match config.exporter {
+        TraceExporter::None => return Ok(()),
+        TraceExporter::Stdout => {
+            opentelemetry::sdk::export::trace::stdout::new_pipeline()
+                .with_trace_config(trace_config().with_resource(resource))
+                .install_simple();
+        }
+        TraceExporter::Otlp => {
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(config.otlp_endpoint.clone()),
+                )
+                .with_trace_config(trace_config().with_resource(resource))
+                .install_batch(opentelemetry::runtime::Tokio)?;
+        }
+    }
+    info!("Tracing: {:?}", config.exporter);
+    Ok(())
+}
+
+/// Exports the spans that are still pending.
+pub fn shutdown_tracing() {
+    global::shutdown_tracer_provider();
+}
+
+/// The trace context that was sent along with a gRPC request.
+pub fn remote_context(metadata: &MetadataMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&MetadataExtractor(metadata)))
+}
+
+/// Runs an RPC in a server span that is a child of the given context, with the method as log field.
+pub async fn traced_rpc<T, F>(method: &str, parent: Context, call: F) -> Result<T, Status>
+where
+    F: Future<Output = Result<T, Status>>,
+{
+    let context = with_method(&child_context(method, SpanKind::Server, &parent), method);
+    let result = call.with_context(context.clone()).await;
+    if let Err(status) = &result {
+        context.span().set_status(SpanStatus::error(format!("{:?}: {}", status.code(), status.message())));
+    }
+    result
+}
+
+/// Starts a span that is a child of the given context, and returns the context of that span.
+///
+/// The span ends when the last clone of the returned context is dropped.
+pub fn child_context(name: &str, kind: SpanKind, parent: &Context) -> Context {
+    let tracer = global::tracer(TRACER_NAME);
+    let span = tracer
+        .span_builder(name.to_string())
+        .with_kind(kind)
+        .start_with_context(&tracer, parent);
+    parent.with_span(span)
+}
+
+/// The W3C trace context fields (`traceparent` and `tracestate`) of the given context.
+pub fn trace_fields(context: &Context) -> HashMap<String, String> {
+    let mut fields = HashMap::new();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(context, &mut fields));
+    fields
+}
+
+/// The context of the remote span that is described by the given W3C trace context fields.
+pub fn trace_context(fields: &HashMap<String, String>) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(fields))
+}
+
+struct MetadataExtractor<'a>(&'a MetadataMap);
+
+impl<'a> Extractor for MetadataExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .keys()
+            .filter_map(|key| match key {
+                tonic::metadata::KeyRef::Ascii(key) => Some(key.as_str()),
+                tonic::metadata::KeyRef::Binary(_) => None,
+            })
+            .collect()
+    }
+}
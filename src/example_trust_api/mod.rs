@@ -0,0 +1,450 @@
+//! Serves the `ConfigurationService`, so that a deployment can manage its own trust store.
+//!
+//! Changes to trusted keys and credentials must be signed with the private key of a key manager. Signatures
+//! use PKCS#1 v1.5 with SHA-256 (format `rsa-sha2-256`) over the following data:
+//!
+//! * `TrustedKeyRequest`: `nonce || name || "\n" || public_key || "\n" || ("key-manager" | "trusted-key")`,
+//!   where `nonce` is the one that the server sent in the preceding `TrustedKeyResponse`.
+//! * `Credentials`: `identifier || "\n" || secret || "\n" || expires_at`, where `expires_at` is the decimal number of
+//!   seconds since the epoch after which the signed request is no longer accepted. It can be at most
+//!   `MAX_CREDENTIALS_VALIDITY_SECONDS` in the future.
+//!
+//! The changes are submitted as commands to the `TrustStoreProjection` aggregate. The resulting events are
+//! picked up by the auth event processor of `dendrite::auth`.
+
+use crate::example_auth::authorize;
+use crate::example_config::ApplicationConfig;
+use crate::example_error::DomainError;
+use crate::example_trust_command::load_trust_store;
+use crate::proto_dendrite_config::configuration_service_server::ConfigurationService;
+use crate::proto_dendrite_config::{
+    AccessToken, ChangePropertyCommand, Credentials, Empty, KeyValue, PrivateKey, PublicKey,
+    RegisterCredentialsCommand, RegisterKeyManagerCommand, RegisterTrustedKeyCommand, Signature,
+    Status as ResponseStatus, TrustStoreProjection, TrustedKeyRequest, TrustedKeyResponse,
+};
+use anyhow::{anyhow, Result};
+use dendrite::axon_utils::{AxonServerHandle, SubmitCommand};
+use futures_core::stream::Stream;
+use jwt::{AlgorithmType, Header, SignWithKey, SigningAlgorithm, Token};
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::{BigUint, PaddingScheme, PublicKey as _, RsaPrivateKey, RsaPublicKey};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+use tokio::sync::mpsc;
+use tonic::{Code, Request, Response, Status, Streaming};
+use uuid::Uuid;
+
+const SIGNATURE_FORMAT: &str = "rsa-sha2-256";
+const SECRET_SCHEME: &str = "sha256";
+
+/// How far in the future the expiry of a signed `Credentials` request can be, to limit replays of captured requests.
+const MAX_CREDENTIALS_VALIDITY_SECONDS: u64 = 300;
+
+lazy_static! {
+    static ref BOOTSTRAP_KEYS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+    static ref SIGNING_KEY: Mutex<Option<(String, RsaPrivateKey)>> = Mutex::new(None);
+}
+
+/// Carries an `AxonServerHandle` and implements the `prost` generated `ConfigurationService`.
+#[derive(Debug)]
+pub struct TrustStoreServer {
+    pub axon_server_handle: AxonServerHandle,
+    pub config: Arc<ApplicationConfig>,
+}
+
+#[tonic::async_trait]
+impl ConfigurationService for TrustStoreServer {
+    type ListTrustedKeysStream =
+        Pin<Box<dyn Stream<Item = Result<PublicKey, Status>> + Send + Sync + 'static>>;
+
+    async fn list_trusted_keys(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<Self::ListTrustedKeysStream>, Status> {
+        let trust_store = self.trust_store().await?;
+        let trusted_keys: BTreeMap<String, String> = trust_store.trusted_keys.into_iter().collect();
+        let output = async_stream::try_stream! {
+            for (name, public_key) in trusted_keys {
+                yield PublicKey { public_key, name };
+            }
+        };
+        Ok(Response::new(Box::pin(output) as Self::ListTrustedKeysStream))
+    }
+
+    type ChangeTrustedKeysStream =
+        Pin<Box<dyn Stream<Item = Result<TrustedKeyResponse, Status>> + Send + Sync + 'static>>;
+
+    async fn change_trusted_keys(
+        &self,
+        request: Request<Streaming<TrustedKeyRequest>>,
+    ) -> Result<Response<Self::ChangeTrustedKeysStream>, Status> {
+        let mut inbound = request.into_inner();
+        let (tx, mut rx): (
+            mpsc::Sender<TrustedKeyResponse>,
+            mpsc::Receiver<TrustedKeyResponse>,
+        ) = mpsc::channel(self.config.channels.trusted_keys);
+        let server = TrustStoreServer {
+            axon_server_handle: self.axon_server_handle.clone(),
+            config: self.config.clone(),
+        };
+
+        tokio::spawn(async move {
+            let mut nonce = new_nonce();
+            let ready = TrustedKeyResponse {
+                status: Some(response_status(Ok(()))),
+                nonce: nonce.clone(),
+            };
+            if tx.send(ready).await.is_err() {
+                return;
+            }
+            loop {
+                let trusted_key_request = match inbound.message().await {
+                    Ok(Some(trusted_key_request)) => trusted_key_request,
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("Error while receiving trusted key requests: {:?}", e);
+                        break;
+                    }
+                };
+                let result = server.change_trusted_key(trusted_key_request, &nonce).await;
+                nonce = new_nonce();
+                let response = TrustedKeyResponse {
+                    status: Some(response_status(result)),
+                    nonce: nonce.clone(),
+                };
+                if tx.send(response).await.is_err() {
+                    break;
+                }
+            }
+            debug!("End of trusted key requests");
+        });
+
+        let output = async_stream::try_stream! {
+            while let Some(value) = rx.recv().await {
+                yield value as TrustedKeyResponse;
+            }
+        };
+
+        Ok(Response::new(Box::pin(output) as Self::ChangeTrustedKeysStream))
+    }
+
+    async fn set_private_key(&self, request: Request<PrivateKey>) -> Result<Response<Empty>, Status> {
+        let private_key = request.into_inner();
+        debug!("Set private key: {:?}", private_key.name);
+        let rsa_private_key = RsaPrivateKey::from_pkcs8_pem(&private_key.private_key)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&private_key.private_key))
+            .map_err(|_| Status::invalid_argument("Invalid private key: expected PKCS#8 or PKCS#1 PEM"))?;
+        let trust_store = self.trust_store().await?;
+        let trusted_key = trust_store
+            .trusted_keys
+            .get(&private_key.name)
+            .ok_or_else(|| Status::permission_denied(format!("Unknown trusted key: {}", private_key.name)))?;
+        let trusted_key = rsa_public_key(trusted_key).map_err(|e| Status::internal(e.to_string()))?;
+        if RsaPublicKey::from(&rsa_private_key) != trusted_key {
+            return Err(Status::permission_denied(format!(
+                "Private key does not match trusted key: {}",
+                private_key.name
+            )));
+        }
+        let mut signing_key = SIGNING_KEY.lock().map_err(|e| Status::internal(e.to_string()))?;
+        *signing_key = Some((private_key.name, rsa_private_key));
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn change_credentials(
+        &self,
+        request: Request<Streaming<Credentials>>,
+    ) -> Result<Response<Empty>, Status> {
+        let mut inbound = request.into_inner();
+        while let Some(credentials) = inbound.message().await? {
+            debug!("Change credentials: {:?}", credentials.identifier);
+            if !credentials.secret.is_empty() && parse_secret(&credentials.secret).is_none() {
+                return Err(Status::invalid_argument(format!(
+                    "Malformed secret for: {}",
+                    credentials.identifier
+                )));
+            }
+            check_expiry(credentials.expires_at)?;
+            let trust_store = self.trust_store().await?;
+            let data = [
+                credentials.identifier.as_bytes(),
+                b"\n",
+                credentials.secret.as_bytes(),
+                b"\n",
+                credentials.expires_at.to_string().as_bytes(),
+            ]
+            .concat();
+            verify_signature(&trust_store, credentials.signature.as_ref(), &data)?;
+            let command = RegisterCredentialsCommand {
+                credentials: Some(credentials),
+            };
+            SubmitCommand::new("RegisterCredentialsCommand", Box::new(command))
+                .send(&self.axon_server_handle)
+                .await
+                .map_err(to_status)?;
+        }
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn authorize(&self, request: Request<Credentials>) -> Result<Response<AccessToken>, Status> {
+        let credentials = request.into_inner();
+        debug!("Authorize: {:?}", credentials.identifier);
+        let trust_store = self.trust_store().await?;
+        let verified = trust_store
+            .credentials
+            .get(&credentials.identifier)
+            .map(|secret| verify_password(secret, &credentials.secret))
+            .unwrap_or(false);
+        if !verified {
+            warn!("Invalid credentials: {:?}", credentials.identifier);
+            return Err(Status::unauthenticated("Invalid credentials"));
+        }
+        let roles_key = format!("{}{}", self.config.auth.roles_property_prefix, credentials.identifier);
+        let roles = trust_store.properties.get(&roles_key).cloned().unwrap_or_default();
+        let jwt = issue_token(&credentials.identifier, &roles, self.config.auth.token_lifetime_seconds)?;
+        Ok(Response::new(AccessToken { jwt }))
+    }
+
+    async fn set_property(&self, request: Request<KeyValue>) -> Result<Response<Empty>, Status> {
+        authorize(&request, "SetProperty")?;
+        let property = request.into_inner();
+        debug!("Set property: {:?}", property.key);
+        let command = ChangePropertyCommand {
+            property: Some(property),
+        };
+        SubmitCommand::new("ChangePropertyCommand", Box::new(command))
+            .send(&self.axon_server_handle)
+            .await
+            .map_err(to_status)?;
+        Ok(Response::new(Empty {}))
+    }
+}
+
+impl TrustStoreServer {
+    /// The current trust store, supplemented with the keys that were registered at startup.
+    async fn trust_store(&self) -> Result<TrustStoreProjection, Status> {
+        let mut trust_store = load_trust_store(&self.axon_server_handle)
+            .await
+            .map_err(to_status)?;
+        let bootstrap_keys = BOOTSTRAP_KEYS.lock().map_err(|e| Status::internal(e.to_string()))?;
+        for (name, public_key) in bootstrap_keys.iter() {
+            trust_store
+                .trusted_keys
+                .entry(name.clone())
+                .or_insert_with(|| public_key.clone());
+            trust_store
+                .key_managers
+                .entry(name.clone())
+                .or_insert_with(|| public_key.clone());
+        }
+        Ok(trust_store)
+    }
+
+    async fn change_trusted_key(&self, request: TrustedKeyRequest, nonce: &[u8]) -> Result<(), Status> {
+        if request.nonce != nonce {
+            return Err(Status::invalid_argument("Invalid nonce"));
+        }
+        let public_key = request
+            .public_key
+            .ok_or_else(|| Status::invalid_argument("Missing public key"))?;
+        debug!("Change trusted key: {:?}: {:?}", public_key.name, request.is_key_manager);
+        if !public_key.public_key.is_empty() {
+            rsa_public_key(&public_key.public_key)
+                .map_err(|_| Status::invalid_argument(format!("Invalid public key: {}", public_key.name)))?;
+        }
+        let kind: &[u8] = if request.is_key_manager { b"key-manager" } else { b"trusted-key" };
+        let data = [
+            nonce,
+            public_key.name.as_bytes(),
+            b"\n",
+            public_key.public_key.as_bytes(),
+            b"\n",
+            kind,
+        ]
+        .concat();
+        let trust_store = self.trust_store().await?;
+        verify_signature(&trust_store, request.signature.as_ref(), &data)?;
+        let submit = if request.is_key_manager {
+            SubmitCommand::new(
+                "RegisterKeyManagerCommand",
+                Box::new(RegisterKeyManagerCommand {
+                    public_key: Some(public_key),
+                }),
+            )
+        } else {
+            SubmitCommand::new(
+                "RegisterTrustedKeyCommand",
+                Box::new(RegisterTrustedKeyCommand {
+                    public_key: Some(public_key),
+                }),
+            )
+        };
+        submit.send(&self.axon_server_handle).await.map_err(to_status)?;
+        Ok(())
+    }
+}
+
+/// Replaces the keys that are trusted, and act as key managers, from the start.
+///
+/// Bootstrap keys are not stored in AxonServer, and they can't be removed through the `ConfigurationService`.
+/// Returns the previous bootstrap keys.
+pub fn set_bootstrap_keys(keys: BTreeMap<String, String>) -> Result<BTreeMap<String, String>> {
+    let mut bootstrap_keys = BOOTSTRAP_KEYS.lock().map_err(|e| anyhow!(e.to_string()))?;
+    Ok(std::mem::replace(&mut *bootstrap_keys, keys))
+}
+
+/// Parses the base64 encoded body of an OpenSSH `ssh-rsa` public key.
+pub fn rsa_public_key(public_key: &str) -> Result<RsaPublicKey> {
+    let key = format!("ssh-rsa {}", public_key);
+    match sshkeys::PublicKey::from_string(&key)? {
+        sshkeys::PublicKey {
+            kind: sshkeys::PublicKeyKind::Rsa(sshkeys::RsaPublicKey { n, e }),
+            ..
+        } => Ok(RsaPublicKey::new(
+            BigUint::from_bytes_be(&n),
+            BigUint::from_bytes_be(&e),
+        )?),
+        _ => Err(anyhow!("Not an RSA public key")),
+    }
+}
+
+fn verify_signature(trust_store: &TrustStoreProjection, signature: Option<&Signature>, data: &[u8]) -> Result<(), Status> {
+    let signature = signature.ok_or_else(|| Status::unauthenticated("Missing signature"))?;
+    if !signature.format.is_empty() && signature.format != SIGNATURE_FORMAT {
+        return Err(Status::invalid_argument(format!(
+            "Unsupported signature format: {}",
+            signature.format
+        )));
+    }
+    let key_manager = trust_store
+        .key_managers
+        .get(&signature.signature_name)
+        .ok_or_else(|| Status::permission_denied(format!("Unknown key manager: {}", signature.signature_name)))?;
+    let key_manager = rsa_public_key(key_manager).map_err(|e| Status::internal(e.to_string()))?;
+    let hashed = Sha256::digest(data);
+    key_manager
+        .verify(PaddingScheme::new_pkcs1v15_sign::<Sha256>(), &hashed, &signature.blob)
+        .map_err(|_| {
+            warn!("Invalid signature: {:?}", signature.signature_name);
+            Status::permission_denied("Invalid signature")
+        })
+}
+
+/// Rejects a signed request that has expired, or that would stay valid for too long.
+fn check_expiry(expires_at: u64) -> Result<(), Status> {
+    let now = now_seconds()?;
+    if expires_at <= now {
+        return Err(Status::permission_denied("Signed request has expired"));
+    }
+    if expires_at > now + MAX_CREDENTIALS_VALIDITY_SECONDS {
+        return Err(Status::invalid_argument(format!(
+            "Expiry of signed request is more than {}s in the future",
+            MAX_CREDENTIALS_VALIDITY_SECONDS
+        )));
+    }
+    Ok(())
+}
+
+/// Splits a secret of the form `sha256:<salt>:<hash>` into the decoded salt and hash.
+fn parse_secret(secret: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+    let mut parts = secret.split(':');
+    if parts.next()? != SECRET_SCHEME {
+        return None;
+    }
+    let salt = base64::decode_config(parts.next()?, base64::STANDARD_NO_PAD).ok()?;
+    let hash = base64::decode_config(parts.next()?, base64::STANDARD_NO_PAD).ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((salt, hash))
+}
+
+fn verify_password(secret: &str, password: &str) -> bool {
+    match parse_secret(secret) {
+        Some((salt, hash)) => {
+            let mut hasher = Sha256::new();
+            hasher.update(&salt);
+            hasher.update(password.as_bytes());
+            bool::from(hasher.finalize().as_slice().ct_eq(hash.as_slice()))
+        }
+        None => false,
+    }
+}
+
+struct JwtSigningKey<'a>(&'a RsaPrivateKey);
+
+impl SigningAlgorithm for JwtSigningKey<'_> {
+    fn algorithm_type(&self) -> AlgorithmType {
+        AlgorithmType::Rs256
+    }
+
+    fn sign(&self, header: &str, claims: &str) -> Result<String, jwt::Error> {
+        let mut hasher = Sha256::new();
+        hasher.update(header.as_bytes());
+        hasher.update(b".");
+        hasher.update(claims.as_bytes());
+        let signature = self
+            .0
+            .sign(PaddingScheme::new_pkcs1v15_sign::<Sha256>(), &hasher.finalize())
+            .map_err(|_| jwt::Error::InvalidSignature)?;
+        Ok(base64::encode_config(signature, base64::URL_SAFE_NO_PAD))
+    }
+}
+
+fn issue_token(subject: &str, roles: &str, lifetime_seconds: u64) -> Result<String, Status> {
+    let signing_key = SIGNING_KEY.lock().map_err(|e| Status::internal(e.to_string()))?;
+    let (key_name, private_key) = signing_key
+        .as_ref()
+        .ok_or_else(|| Status::failed_precondition("No signing key"))?;
+    let now = now_seconds()?;
+    let header = Header {
+        algorithm: AlgorithmType::Rs256,
+        key_id: Some(key_name.clone()),
+        ..Header::default()
+    };
+    let mut claims: BTreeMap<&str, Value> = BTreeMap::new();
+    claims.insert("sub", Value::from(subject));
+    claims.insert("roles", Value::from(roles));
+    claims.insert("iat", Value::from(now));
+    claims.insert("exp", Value::from(now + lifetime_seconds));
+    let token = Token::new(header, claims)
+        .sign_with_key(&JwtSigningKey(private_key))
+        .map_err(|e| Status::internal(e.to_string()))?;
+    Ok(token.as_str().to_string())
+}
+
+fn now_seconds() -> Result<u64, Status> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| Status::internal(e.to_string()))?
+        .as_secs())
+}
+
+fn new_nonce() -> Vec<u8> {
+    Uuid::new_v4().as_bytes().to_vec()
+}
+
+fn response_status(result: Result<(), Status>) -> ResponseStatus {
+    match result {
+        Ok(()) => ResponseStatus {
+            code: Code::Ok as i32,
+            message: "OK".to_string(),
+        },
+        Err(status) => ResponseStatus {
+            code: status.code() as i32,
+            message: status.message().to_string(),
+        },
+    }
+}
+
+fn to_status(e: anyhow::Error) -> Status {
+    DomainError::from(&e).to_status()
+}
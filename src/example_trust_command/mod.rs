@@ -0,0 +1,283 @@
+use crate::example_config::ApplicationConfig;
+use crate::example_error::DomainError;
+use crate::example_supervisor::supervise;
+use crate::proto_dendrite_config::{
+    ChangePropertyCommand, Credentials, CredentialsAddedEvent, CredentialsRemovedEvent, Empty,
+    KeyManagerAddedEvent, KeyManagerRemovedEvent, PropertyChangedEvent, PublicKey,
+    RegisterCredentialsCommand, RegisterKeyManagerCommand, RegisterTrustedKeyCommand,
+    TrustStoreProjection, TrustedKeyAddedEvent, TrustedKeyRemovedEvent,
+};
+use anyhow::{anyhow, Context, Result};
+use async_lock::Mutex;
+use dendrite::axon_server::command::Command;
+use dendrite::axon_server::event::event_store_client::EventStoreClient;
+use dendrite::axon_server::event::{Event, GetAggregateEventsRequest};
+use dendrite::axon_utils::{command_worker, create_aggregate_definition, empty_aggregate_registry, empty_handler_registry, AggregateContext, AggregateContextTrait, AggregateDefinition, AggregateRegistry, ApplicableTo, AxonServerHandle, HandlerRegistry, SerializedObject, TheHandlerRegistry, WorkerControl, WorkerThread};
+use dendrite::intellij_work_around::Debuggable;
+use dendrite::macros as dendrite_macros;
+use lazy_static::lazy_static;
+use log::debug;
+use prost::Message;
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// The identifier of the single `TrustStoreProjection` aggregate.
+pub const TRUST_STORE_AGGREGATE_ID: &str = "dendrite-trust-store";
+
+lazy_static! {
+    /// The sequence number of the last event that was applied, and the resulting projection of the trust store.
+    static ref TRUST_STORE: Mutex<(i64, TrustStoreProjection)> = Mutex::new((-1, empty_projection()));
+}
+
+/// Creates a supervised worker that handles the commands that change the trust store.
+pub fn handle_trust_commands_with(config: Arc<ApplicationConfig>) -> WorkerThread {
+    let worker_config = config.clone();
+    supervise("trust_command", &config, move |handle, worker_control| {
+        Box::pin(internal_handle_trust_commands(handle, worker_config.clone(), worker_control))
+    })
+}
+
+/// Handles commands that change the trust store.
+///
+/// Constructs an aggregate registry and delegates to function `command_worker`.
+async fn internal_handle_trust_commands(axon_server_handle: AxonServerHandle, config: Arc<ApplicationConfig>, worker_control: WorkerControl) -> Result<()> {
+    debug!("Handle trust store commands: {:?}: {:?}", worker_control.get_label(), config.workers.trust_command);
+
+    let mut command_handler_registry: TheHandlerRegistry<
+        Arc<Mutex<AggregateContext<TrustStoreProjection>>>,
+        Command,
+        SerializedObject,
+    > = empty_handler_registry();
+
+    command_handler_registry.register(&handle_register_trusted_key_command)?;
+    command_handler_registry.register(&handle_register_key_manager_command)?;
+    command_handler_registry.register(&handle_register_credentials_command)?;
+    command_handler_registry.register(&handle_change_property_command)?;
+
+    let aggregate_definition: AggregateDefinition<TrustStoreProjection> = create_aggregate_definition(
+        "TrustStoreProjection".to_string(),
+        Box::from(empty_projection as fn() -> TrustStoreProjection),
+        command_handler_registry,
+        sourcing_handler_registry()?,
+    );
+
+    let mut aggregate_registry = empty_aggregate_registry();
+    aggregate_registry.insert(Arc::new(Arc::new(aggregate_definition)))?;
+
+    command_worker(axon_server_handle, &mut aggregate_registry, worker_control)
+        .await
+        .context("Error while handling trust store commands")
+}
+
+/// The current state of the trust store.
+///
+/// The projection is cached. Only the events that were appended since the previous call are read from AxonServer and
+/// applied, so that frequent calls, _e.g._, for every `Authorize`, don't replay the full history.
+pub async fn load_trust_store(axon_server_handle: &AxonServerHandle) -> Result<TrustStoreProjection> {
+    let mut trust_store = TRUST_STORE.lock().await;
+    let request = GetAggregateEventsRequest {
+        aggregate_id: TRUST_STORE_AGGREGATE_ID.to_string(),
+        allow_snapshots: false,
+        initial_sequence: trust_store.0 + 1,
+        max_sequence: i64::MAX,
+        min_token: 0,
+    };
+    let mut client = EventStoreClient::new(axon_server_handle.conn.clone());
+    let mut events = client.list_aggregate_events(request).await?.into_inner();
+    let registry = sourcing_handler_registry()?;
+    while let Some(event) = events.message().await? {
+        let seq = event.aggregate_sequence_number;
+        let payload = match event.payload.clone() {
+            Some(payload) => payload,
+            None => {
+                trust_store.0 = seq;
+                continue;
+            }
+        };
+        let sourcing_handler = registry
+            .get(&payload.r#type)
+            .ok_or_else(|| anyhow!("Missing sourcing handler for {:?}", payload.r#type))?;
+        if let Some(projection) = sourcing_handler
+            .handle(payload.data, event, trust_store.1.clone())
+            .await?
+        {
+            trust_store.1 = projection;
+        }
+        trust_store.0 = seq;
+    }
+    Ok(trust_store.1.clone())
+}
+
+fn sourcing_handler_registry() -> Result<TheHandlerRegistry<TrustStoreProjection, Event, TrustStoreProjection>> {
+    let mut sourcing_handler_registry = empty_handler_registry();
+    sourcing_handler_registry.register(&handle_trusted_key_added_source_event)?;
+    sourcing_handler_registry.register(&handle_trusted_key_removed_source_event)?;
+    sourcing_handler_registry.register(&handle_key_manager_added_source_event)?;
+    sourcing_handler_registry.register(&handle_key_manager_removed_source_event)?;
+    sourcing_handler_registry.register(&handle_credentials_added_source_event)?;
+    sourcing_handler_registry.register(&handle_credentials_removed_source_event)?;
+    sourcing_handler_registry.register(&handle_property_changed_source_event)?;
+    Ok(sourcing_handler_registry)
+}
+
+fn empty_projection() -> TrustStoreProjection {
+    TrustStoreProjection::default()
+}
+
+#[dendrite_macros::command_handler]
+async fn handle_register_trusted_key_command(
+    command: RegisterTrustedKeyCommand,
+    aggregate_context: &mut AggregateContext<TrustStoreProjection>,
+) -> Result<Option<Empty>> {
+    debug!("Register trusted key command handler: {:?}", Debuggable::from(&command));
+    let public_key = named_public_key(command.public_key)?;
+    let projection = aggregate_context.get_projection(TRUST_STORE_AGGREGATE_ID).await?;
+    let existing = projection.trusted_keys.get(&public_key.name);
+    if public_key.public_key.is_empty() {
+        if existing.is_none() {
+            debug!("Unnecessary removal of trusted key: {:?}", public_key.name);
+            return Ok(None);
+        }
+        aggregate_context.emit("TrustedKeyRemovedEvent", Box::new(TrustedKeyRemovedEvent { name: public_key.name }))?;
+    } else {
+        if existing == Some(&public_key.public_key) {
+            debug!("Unnecessary registration of trusted key: {:?}", public_key.name);
+            return Ok(None);
+        }
+        aggregate_context.emit("TrustedKeyAddedEvent", Box::new(TrustedKeyAddedEvent { public_key: Some(public_key) }))?;
+    }
+    Ok(Some(Empty::default()))
+}
+
+#[dendrite_macros::command_handler]
+async fn handle_register_key_manager_command(
+    command: RegisterKeyManagerCommand,
+    aggregate_context: &mut AggregateContext<TrustStoreProjection>,
+) -> Result<Option<Empty>> {
+    debug!("Register key manager command handler: {:?}", Debuggable::from(&command));
+    let public_key = named_public_key(command.public_key)?;
+    let projection = aggregate_context.get_projection(TRUST_STORE_AGGREGATE_ID).await?;
+    let existing = projection.key_managers.get(&public_key.name);
+    if public_key.public_key.is_empty() {
+        if existing.is_none() {
+            debug!("Unnecessary removal of key manager: {:?}", public_key.name);
+            return Ok(None);
+        }
+        aggregate_context.emit("KeyManagerRemovedEvent", Box::new(KeyManagerRemovedEvent { name: public_key.name }))?;
+    } else {
+        if existing == Some(&public_key.public_key) {
+            debug!("Unnecessary registration of key manager: {:?}", public_key.name);
+            return Ok(None);
+        }
+        aggregate_context.emit("KeyManagerAddedEvent", Box::new(KeyManagerAddedEvent { public_key: Some(public_key) }))?;
+    }
+    Ok(Some(Empty::default()))
+}
+
+#[dendrite_macros::command_handler]
+async fn handle_register_credentials_command(
+    command: RegisterCredentialsCommand,
+    aggregate_context: &mut AggregateContext<TrustStoreProjection>,
+) -> Result<Option<Empty>> {
+    let credentials = command
+        .credentials
+        .ok_or_else(|| DomainError::InvalidArgument("Missing credentials".to_string()))?;
+    debug!("Register credentials command handler: {:?}", credentials.identifier);
+    if credentials.identifier.is_empty() {
+        return Err(DomainError::InvalidArgument("Missing identifier".to_string()).into());
+    }
+    let projection = aggregate_context.get_projection(TRUST_STORE_AGGREGATE_ID).await?;
+    let existing = projection.credentials.get(&credentials.identifier);
+    if credentials.secret.is_empty() {
+        if existing.is_none() {
+            debug!("Unnecessary removal of credentials: {:?}", credentials.identifier);
+            return Ok(None);
+        }
+        aggregate_context.emit("CredentialsRemovedEvent", Box::new(CredentialsRemovedEvent { identifier: credentials.identifier }))?;
+    } else {
+        if existing == Some(&credentials.secret) {
+            debug!("Unnecessary registration of credentials: {:?}", credentials.identifier);
+            return Ok(None);
+        }
+        let credentials = Credentials {
+            identifier: credentials.identifier,
+            secret: credentials.secret,
+            signature: None,
+            expires_at: 0,
+        };
+        aggregate_context.emit("CredentialsAddedEvent", Box::new(CredentialsAddedEvent { credentials: Some(credentials) }))?;
+    }
+    Ok(Some(Empty::default()))
+}
+
+#[dendrite_macros::command_handler]
+async fn handle_change_property_command(
+    command: ChangePropertyCommand,
+    aggregate_context: &mut AggregateContext<TrustStoreProjection>,
+) -> Result<Option<Empty>> {
+    debug!("Change property command handler: {:?}", Debuggable::from(&command));
+    let property = command
+        .property
+        .ok_or_else(|| DomainError::InvalidArgument("Missing property".to_string()))?;
+    if property.key.is_empty() {
+        return Err(DomainError::InvalidArgument("Missing property key".to_string()).into());
+    }
+    let projection = aggregate_context.get_projection(TRUST_STORE_AGGREGATE_ID).await?;
+    if projection.properties.get(&property.key) == Some(&property.value) {
+        debug!("Unnecessary change of property: {:?}", property.key);
+        return Ok(None);
+    }
+    aggregate_context.emit("PropertyChangedEvent", Box::new(PropertyChangedEvent { property: Some(property) }))?;
+    Ok(Some(Empty::default()))
+}
+
+fn named_public_key(public_key: Option<PublicKey>) -> Result<PublicKey> {
+    let public_key = public_key.ok_or_else(|| DomainError::InvalidArgument("Missing public key".to_string()))?;
+    if public_key.name.is_empty() {
+        return Err(DomainError::InvalidArgument("Missing key name".to_string()).into());
+    }
+    Ok(public_key)
+}
+
+#[dendrite_macros::event_sourcing_handler]
+fn handle_trusted_key_added_source_event(event: TrustedKeyAddedEvent, mut projection: TrustStoreProjection) {
+    if let Some(public_key) = event.public_key {
+        projection.trusted_keys.insert(public_key.name, public_key.public_key);
+    }
+}
+
+#[dendrite_macros::event_sourcing_handler]
+fn handle_trusted_key_removed_source_event(event: TrustedKeyRemovedEvent, mut projection: TrustStoreProjection) {
+    projection.trusted_keys.remove(&event.name);
+}
+
+#[dendrite_macros::event_sourcing_handler]
+fn handle_key_manager_added_source_event(event: KeyManagerAddedEvent, mut projection: TrustStoreProjection) {
+    if let Some(public_key) = event.public_key {
+        projection.key_managers.insert(public_key.name, public_key.public_key);
+    }
+}
+
+#[dendrite_macros::event_sourcing_handler]
+fn handle_key_manager_removed_source_event(event: KeyManagerRemovedEvent, mut projection: TrustStoreProjection) {
+    projection.key_managers.remove(&event.name);
+}
+
+#[dendrite_macros::event_sourcing_handler]
+fn handle_credentials_added_source_event(event: CredentialsAddedEvent, mut projection: TrustStoreProjection) {
+    if let Some(credentials) = event.credentials {
+        projection.credentials.insert(credentials.identifier, credentials.secret);
+    }
+}
+
+#[dendrite_macros::event_sourcing_handler]
+fn handle_credentials_removed_source_event(event: CredentialsRemovedEvent, mut projection: TrustStoreProjection) {
+    projection.credentials.remove(&event.identifier);
+}
+
+#[dendrite_macros::event_sourcing_handler]
+fn handle_property_changed_source_event(event: PropertyChangedEvent, mut projection: TrustStoreProjection) {
+    if let Some(property) = event.property {
+        projection.properties.insert(property.key, property.value);
+    }
+}
@@ -0,0 +1,156 @@
+//! Loads the trusted public keys at startup, and reloads them on SIGHUP.
+//!
+//! Keys are read in OpenSSH public key format (`ssh-rsa <base64> <name>`) from the files listed in
+//! `trusted_keys.files`, from every `*.pub` file in the directories listed in `trusted_keys.directories`, and
+//! from the lines of the environment variable that is named by `trusted_keys.variable`. Each key is registered
+//! both as a trusted key and as a key manager. A key without a comment is named after its file, or `key-<n>`
+//! if it came from the environment.
+//!
+//! A key that is no longer found on reload is revoked: it is no longer accepted for JWT verification, nor as a key
+//! manager.
+
+use crate::example_config::{ApplicationConfig, TrustedKeysConfig};
+use crate::example_trust_api::set_bootstrap_keys;
+use anyhow::{anyhow, Context, Result};
+use dendrite::auth as dendrite_auth;
+use dendrite::auth::dendrite_config::PublicKey;
+use log::{debug, error, info, warn};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+
+const PUBLIC_KEY_EXTENSION: &str = "pub";
+
+/// Loads the trusted keys and registers them with `dendrite::auth`.
+///
+/// Returns the number of keys that were registered.
+pub fn load_trusted_keys(config: &TrustedKeysConfig) -> Result<usize> {
+    let keys = collect_trusted_keys(config)?;
+    if keys.is_empty() {
+        warn!("No trusted keys found");
+    }
+    for (name, public_key) in &keys {
+        debug!("Trusted key: {:?}", name);
+        let public_key = PublicKey {
+            name: name.clone(),
+            public_key: public_key.clone(),
+        };
+        dendrite_auth::unchecked_set_public_key(public_key.clone())?;
+        dendrite_auth::unchecked_set_key_manager(public_key)?;
+    }
+    let count = keys.len();
+    let previous_keys = set_bootstrap_keys(keys.clone())?;
+    for name in previous_keys.keys().filter(|name| !keys.contains_key(*name)) {
+        info!("Revoke trusted key: {:?}", name);
+        revoke_key(name)?;
+    }
+    Ok(count)
+}
+
+/// Makes `dendrite::auth` reject the key with the given name.
+///
+/// `dendrite::auth` only removes keys when it processes a `TrustedKeyRemovedEvent` or `KeyManagerRemovedEvent` from
+/// the event store, and bootstrap keys are not stored there. So the key is replaced by an empty key instead: a JWT that
+/// names it fails verification, because an empty key can't be parsed.
+fn revoke_key(name: &str) -> Result<()> {
+    let revoked = PublicKey {
+        name: name.to_string(),
+        public_key: String::new(),
+    };
+    dendrite_auth::unchecked_set_public_key(revoked.clone())?;
+    dendrite_auth::unchecked_set_key_manager(revoked)?;
+    Ok(())
+}
+
+/// Reloads the trusted keys whenever the process receives SIGHUP.
+pub fn reload_on_hangup(config: Arc<ApplicationConfig>) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("Reload trusted keys");
+            match load_trusted_keys(&config.trusted_keys) {
+                Ok(count) => info!("Reloaded trusted keys: {:?}", count),
+                Err(e) => error!("Error while reloading trusted keys: {:?}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn collect_trusted_keys(config: &TrustedKeysConfig) -> Result<BTreeMap<String, String>> {
+    let mut keys = BTreeMap::new();
+    for file in &config.files {
+        read_key_file(Path::new(file), &mut keys)?;
+    }
+    for directory in &config.directories {
+        let directory = Path::new(directory);
+        if !directory.is_dir() {
+            warn!("Trusted keys directory not found: {:?}", directory);
+            continue;
+        }
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(directory).with_context(|| format!("Can't read directory: {:?}", directory))? {
+            let path = entry?.path();
+            if path.extension().map(|extension| extension == PUBLIC_KEY_EXTENSION).unwrap_or(false) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        for path in paths {
+            read_key_file(&path, &mut keys)?;
+        }
+    }
+    if !config.variable.is_empty() {
+        if let Ok(value) = env::var(&config.variable) {
+            for (n, line) in key_lines(&value).enumerate() {
+                let default_name = format!("key-{}", n + 1);
+                let (name, public_key) = parse_key(line, &default_name)
+                    .with_context(|| format!("Invalid key in environment variable: {:?}", config.variable))?;
+                keys.insert(name, public_key);
+            }
+        }
+    }
+    Ok(keys)
+}
+
+fn read_key_file(path: &Path, keys: &mut BTreeMap<String, String>) -> Result<()> {
+    let text = fs::read_to_string(path).with_context(|| format!("Can't read trusted key file: {:?}", path))?;
+    let default_name = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| anyhow!("Invalid trusted key file name: {:?}", path))?
+        .to_string();
+    for line in key_lines(&text) {
+        let (name, public_key) = parse_key(line, &default_name)
+            .with_context(|| format!("Invalid key in file: {:?}", path))?;
+        keys.insert(name, public_key);
+    }
+    Ok(())
+}
+
+fn key_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// Parses a single OpenSSH public key and returns its name and its base64 encoded body.
+fn parse_key(line: &str, default_name: &str) -> Result<(String, String)> {
+    let parsed = sshkeys::PublicKey::from_string(line)?;
+    if !matches!(parsed.kind, sshkeys::PublicKeyKind::Rsa(_)) {
+        return Err(anyhow!("Not an RSA public key: {:?}", parsed.key_type.name));
+    }
+    let public_key = line
+        .split_whitespace()
+        .nth(1)
+        .ok_or_else(|| anyhow!("Missing key data"))?
+        .to_string();
+    let name = parsed
+        .comment
+        .filter(|comment| !comment.is_empty())
+        .unwrap_or_else(|| default_name.to_string());
+    Ok((name, public_key))
+}
@@ -4,10 +4,32 @@
 //!
 //! See the GitHub project [dendrite2go/dendrite](https://github.com/dendrite2go/archetype-rust-axon) for an example of how to use this code.
 
+// The gRPC API dictates `tonic::Status` as the error type of interceptors and handlers.
+#![allow(clippy::result_large_err)]
+
 pub mod application;
+pub mod example_aggregate;
 pub mod example_api;
+pub mod example_auth;
 pub mod example_command;
+pub mod example_config;
+pub mod example_correlation;
+pub mod example_deadline;
+pub mod example_error;
 pub mod example_event;
+pub mod example_grpc_web;
+pub mod example_health;
+pub mod example_logging;
+pub mod example_metrics;
 pub mod example_query;
+pub mod example_reflection;
+pub mod example_rest;
+pub mod example_shutdown;
+pub mod example_supervisor;
+pub mod example_tls;
+pub mod example_tracing;
+pub mod example_trust_api;
+pub mod example_trust_command;
+pub mod example_trusted_keys;
 pub mod proto_dendrite_config;
 pub mod proto_example;
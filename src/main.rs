@@ -2,10 +2,11 @@ use log::info;
 use std::error::Error;
 
 use dendrite_example::application::application;
+use dendrite_example::example_logging::init_logging;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::init();
+    init_logging()?;
     info!("Dendrite Example API service started");
 
     application().await